@@ -1,32 +1,69 @@
+use std::process::ExitCode;
+
 use rawst_dl::cli::args;
 use rawst_dl::cli::args::Arguments;
 use rawst_dl::cli::args::Command;
 use rawst_dl::core::config::{Config, edit_config};
-use rawst_dl::core::engine::{download, resume_download};
+use rawst_dl::core::engine::{clean, continue_downloads, download, resume_download, verify};
 use rawst_dl::core::errors::RawstErr;
 use rawst_dl::core::history;
 use rawst_dl::core::logger;
+use rawst_dl::core::output;
 
 #[tokio::main]
-async fn main() -> Result<(), RawstErr> {
+async fn main() -> ExitCode {
+    // Errors are shown with their message instead of the Debug output `?` in main would print
+    match start().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+async fn start() -> Result<(), RawstErr> {
     let args = args::get();
-    let config = match Config::load().await {
+    output::set_quiet(args.quiet);
+    output::set_stdout_is_data(args.writes_to_stdout());
+    if args.json {
+        output::set_emitter(output::EventEmitter::Json);
+    }
+
+    let config = match Config::load_from(args.config.clone()).await {
         Ok(config) => config,
-        Err(_) => {
-            let config = Config::default();
+        // Only a missing config is replaced, a broken one is reported instead of overwritten
+        Err(RawstErr::FileError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            let mut config = Config::default();
             config.initialise_files().await?;
+            config.apply_env_overrides()?;
+            config.validate()?;
             config
         }
+        Err(err) => return Err(err),
     };
     
-    if args.verbosity.is_some() || args.log_verbosity.is_some() {
+    if args.terminal_log_level().is_some() || args.log_verbosity.is_some() {
         logger::init(&config, &args).map_err(|_| RawstErr::InitilisationError)?;
     }
 
+    // Old logs shouldn't keep rawst from running
+    match logger::prune_logs(&config) {
+        Ok(0) => {}
+        Ok(deleted) => log::debug!("Deleted {} old logs", deleted),
+        Err(err) => log::warn!("Couldn't delete old logs: {}", err),
+    }
+
     log::trace!("Arguments: {args:?}");
     log::trace!("Config: {config:?}");
 
-    if args.command.is_some() {
+    if args.continue_last || args.continue_all {
+        if args.command.is_some() {
+            return Err(RawstErr::InvalidArgs);
+        }
+
+        continue_downloads(args.continue_all, config).await?;
+    } else if args.command.is_some() {
         run(config, args).await?
     }
 
@@ -36,9 +73,15 @@ async fn main() -> Result<(), RawstErr> {
 async fn run(config: Config, args: Arguments) -> Result<(), RawstErr> {
     if let Some(cmd) = args.command {
         match cmd {
-            Command::Download(args) => download(args, config).await?,
-            Command::Resume(args) => resume_download(args, config).await?,
+            Command::Download(args) => {
+                download(args, config).await?;
+            }
+            Command::Resume(args) => {
+                resume_download(args, config).await?;
+            }
             Command::History(args) => history::check_history_args(args, config).await?,
+            Command::Clean(args) => clean(args, config).await?,
+            Command::Verify(args) => verify(args, config).await?,
             Command::Config => edit_config(config).await?,
         }
     }
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+
+use bytes::Bytes;
+use futures::future;
+use futures::stream::{BoxStream, StreamExt};
+use iri_string::types::IriString;
+use reqwest::header::{HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH};
+
+use crate::core::config::Config;
+use crate::core::errors::RawstErr;
+use crate::core::io::{finalize_file, partial_download_path, save_stream};
+use crate::core::progress::DownloadProgress;
+use crate::core::rate_limiter::RateLimiter;
+use crate::core::task::HttpTask;
+use crate::core::utils::extract_filename_from_header;
+
+/// The bytes of a resource, as they arrive
+pub type Segment = BoxStream<'static, Result<Bytes, RawstErr>>;
+
+/// A protocol files can be downloaded over
+///
+/// Keeps the engine independent of the URL scheme, it picks the backend
+/// with [`Scheme::of`]. Tasks are built from HTTP headers, so other protocols
+/// describe resources with the headers an HTTP server would send.
+pub trait DownloadBackend {
+    /// Size, range support and file name of `iri`, along with the URL it resolved to
+    fn fetch_meta(
+        &self,
+        iri: &IriString,
+        additional_headers: &HashMap<String, String>,
+    ) -> impl Future<Output = Result<(HeaderMap, IriString), RawstErr>> + Send;
+
+    /// The bytes of `iri` from `start` up to `end`, inclusive, or up to its end
+    ///
+    /// Fails rather than sending the bytes from somewhere else.
+    fn segment(
+        &self,
+        iri: &IriString,
+        additional_headers: &HashMap<String, String>,
+        start: u64,
+        end: Option<u64>,
+    ) -> impl Future<Output = Result<Segment, RawstErr>> + Send;
+
+    /// Downloads `task` into `config.download_dir`, continuing after the bytes it already has
+    fn download(
+        &self,
+        task: &HttpTask,
+        progress: &dyn DownloadProgress,
+        config: &Config,
+    ) -> impl Future<Output = Result<(), RawstErr>> + Send;
+}
+
+/// What a backend knows about a resource before downloading it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceMeta {
+    pub length: Option<u64>,
+    pub supports_ranges: bool,
+    pub filename_hint: Option<String>,
+}
+
+impl ResourceMeta {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+
+        let supports_ranges = headers
+            .get(ACCEPT_RANGES)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|unit| unit.trim().eq_ignore_ascii_case("bytes")));
+
+        let filename_hint = extract_filename_from_header(headers).map(|path| path.display().to_string());
+
+        ResourceMeta {
+            length,
+            supports_ranges,
+            filename_hint,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Ftp,
+    File,
+}
+
+impl Scheme {
+    pub fn of(iri: &IriString) -> Result<Scheme, RawstErr> {
+        match iri.scheme_str().to_ascii_lowercase().as_str() {
+            "http" | "https" => Ok(Scheme::Http),
+            "ftp" => Ok(Scheme::Ftp),
+            "file" => Ok(Scheme::File),
+            other => Err(RawstErr::UnsupportedScheme(other.to_string())),
+        }
+    }
+}
+
+/// Downloads `task` in one go with [`DownloadBackend::segment`], for backends without their own strategy
+pub async fn download_sequentially<B: DownloadBackend>(
+    backend: &B,
+    task: &HttpTask,
+    progress: &dyn DownloadProgress,
+    config: &Config,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<(), RawstErr> {
+    let start = task.total_downloaded.load(Ordering::SeqCst);
+    let segment = backend.segment(&task.iri, &task.additional_headers, start, None).await?;
+
+    let partial_path = partial_download_path(task, &config.download_dir);
+    save_stream(task, segment, start > 0, progress, &partial_path, rate_limiter, config.buffer_size).await?;
+
+    finalize_file(task, &partial_path, &config.download_dir.join(&task.filename)).await
+}
+
+/// Stops `segment` once it has sent `length` bytes
+pub fn truncate(segment: Segment, length: u64) -> Segment {
+    segment
+        .scan(length, |remaining, chunk| {
+            let chunk = match chunk {
+                Ok(_) if *remaining == 0 => return future::ready(None),
+                Ok(mut chunk) => {
+                    if chunk.len() as u64 > *remaining {
+                        chunk.truncate(*remaining as usize);
+                    }
+                    *remaining -= chunk.len() as u64;
+                    Ok(chunk)
+                }
+                Err(err) => Err(err),
+            };
+
+            future::ready(Some(chunk))
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::CONTENT_DISPOSITION;
+
+    use super::*;
+
+    fn headers(pairs: &[(reqwest::header::HeaderName, &str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.clone(), value.parse().unwrap())).collect()
+    }
+
+    #[test]
+    fn meta_has_the_announced_length() {
+        let meta = ResourceMeta::from_headers(&headers(&[(CONTENT_LENGTH, "4096"), (ACCEPT_RANGES, "bytes")]));
+
+        assert_eq!(meta, ResourceMeta {
+            length: Some(4096),
+            supports_ranges: true,
+            filename_hint: None,
+        });
+    }
+
+    #[test]
+    fn meta_without_content_length_has_no_length() {
+        let meta = ResourceMeta::from_headers(&headers(&[(CONTENT_DISPOSITION, "attachment; filename=\"report.pdf\"")]));
+
+        assert_eq!(meta, ResourceMeta {
+            length: None,
+            supports_ranges: false,
+            filename_hint: Some("report.pdf".to_string()),
+        });
+    }
+
+    #[test]
+    fn meta_ignores_unparsable_lengths() {
+        for length in ["", "big", "-4096", "4096 bytes"] {
+            let meta = ResourceMeta::from_headers(&headers(&[(CONTENT_LENGTH, length)]));
+
+            assert_eq!(meta.length, None, "Content-Length: {:?}", length);
+        }
+    }
+
+    #[test]
+    fn meta_only_supports_byte_ranges() {
+        let supports_ranges = |value| ResourceMeta::from_headers(&headers(&[(ACCEPT_RANGES, value)])).supports_ranges;
+
+        assert!(supports_ranges("bytes"));
+        assert!(supports_ranges("none, Bytes"));
+        assert!(!supports_ranges("none"));
+        assert!(!supports_ranges("pages"));
+    }
+}
@@ -0,0 +1,59 @@
+use futures::future::BoxFuture;
+
+use crate::core::errors::RawstErr;
+use crate::core::io::Checksum;
+use crate::core::task::HttpTask;
+
+/// Somewhere the expected checksum of a download can come from
+///
+/// The engine asks every source of a task with [`resolve_checksum`], the
+/// first one that knows the checksum is the one verified.
+pub trait ChecksumSource: Send + Sync {
+    /// Names the source when it disagrees with another
+    fn name(&self) -> &'static str;
+
+    /// The checksum `task` should have, `None` if this source doesn't know it
+    fn checksum<'a>(&'a self, task: &'a HttpTask) -> BoxFuture<'a, Result<Option<Checksum>, RawstErr>>;
+}
+
+/// A checksum given along with the URL, with `--checksum` or in a metalink
+pub struct GivenChecksum(pub Option<Checksum>);
+
+impl ChecksumSource for GivenChecksum {
+    fn name(&self) -> &'static str {
+        "the given checksum"
+    }
+
+    fn checksum<'a>(&'a self, _task: &'a HttpTask) -> BoxFuture<'a, Result<Option<Checksum>, RawstErr>> {
+        Box::pin(async { Ok(self.0.clone()) })
+    }
+}
+
+/// Asks `sources` in order for the checksum of `task`
+///
+/// Fails with [`RawstErr::ChecksumConflict`] if two of them give different
+/// digests of the same algorithm, checksums of other algorithms can't be compared.
+pub async fn resolve_checksum(sources: &[&dyn ChecksumSource], task: &HttpTask) -> Result<Option<Checksum>, RawstErr> {
+    let mut found: Vec<(&'static str, Checksum)> = Vec::new();
+
+    for source in sources {
+        let Some(checksum) = source.checksum(task).await? else {
+            continue;
+        };
+
+        let conflict = found.iter().find(|(_, other)| {
+            std::mem::discriminant(other) == std::mem::discriminant(&checksum) && !other.digest().eq_ignore_ascii_case(checksum.digest())
+        });
+        if let Some((other_source, other)) = conflict {
+            return Err(RawstErr::ChecksumConflict {
+                first: format!("{} of {}", other, other_source),
+                second: format!("{} of {}", checksum, source.name()),
+            });
+        }
+
+        log::debug!("{} has {} as its checksum", source.name(), checksum);
+        found.push((source.name(), checksum));
+    }
+
+    Ok(found.into_iter().next().map(|(_, checksum)| checksum))
+}
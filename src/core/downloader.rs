@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use iri_string::types::IriString;
+
+use crate::core::config::{Config, OverwritePolicy, ProxyUrl};
+use crate::core::engine::{DownloadSummary, Engine};
+use crate::core::errors::RawstErr;
+use crate::core::progress::{NoProgress, ProgressReporter};
+use crate::core::utils::validate_headers;
+
+/// Downloads files for other programs, without any of the CLI around it
+///
+/// ```no_run
+/// # async fn example() -> Result<(), rawst_dl::core::errors::RawstErr> {
+/// use rawst_dl::Downloader;
+///
+/// let downloader = Downloader::builder().threads(4).build()?;
+/// let summary = downloader.download("https://example.com/file.iso", "/tmp/file").await?;
+/// println!("{} bytes saved to {:?}", summary.bytes, summary.path);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Downloads are recorded in the history of `config.history_file_path` like
+/// the CLI's, so `rawst resume` can pick up the ones that were interrupted.
+#[derive(Clone)]
+pub struct Downloader {
+    engine: Engine,
+    headers: HashMap<String, String>,
+}
+
+impl Downloader {
+    /// Starts from the default config, without progress output
+    pub fn builder() -> DownloaderBuilder {
+        DownloaderBuilder::default()
+    }
+
+    /// Downloads `url` to `output`, like `rawst download -o <output>`
+    ///
+    /// An existing directory keeps the file's own name. The download goes to
+    /// `download_dir` instead when the directory of `output` doesn't exist.
+    pub async fn download(&self, url: &str, output: impl AsRef<Path>) -> Result<DownloadSummary, RawstErr> {
+        let iri = IriString::try_from(url).map_err(|_| RawstErr::InvalidArgs)?;
+
+        self.engine
+            .clone()
+            .process_url_download(iri, Vec::new(), Some(output.as_ref().to_path_buf()), self.headers.clone(), None)
+            .await
+    }
+}
+
+/// Settings of a [`Downloader`], checked by [`DownloaderBuilder::build`]
+pub struct DownloaderBuilder {
+    config: Config,
+    headers: HashMap<String, String>,
+    // The one of the config unless set
+    overwrite_policy: Option<OverwritePolicy>,
+    progress: Arc<dyn ProgressReporter>,
+}
+
+impl Default for DownloaderBuilder {
+    fn default() -> Self {
+        DownloaderBuilder {
+            config: Config::default(),
+            headers: HashMap::new(),
+            overwrite_policy: None,
+            progress: Arc::new(NoProgress),
+        }
+    }
+}
+
+impl DownloaderBuilder {
+    /// Replaces the whole config, eg. with one from [`Config::load`]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Segments downloaded at once, 1 downloads in a single stream
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    /// Headers sent with every request, on top of any given before
+    pub fn headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    /// Directory downloads go to when their output doesn't name an existing one
+    pub fn download_dir(mut self, download_dir: impl Into<PathBuf>) -> Self {
+        self.config.download_dir = download_dir.into();
+        self
+    }
+
+    /// Maximum download speed in bytes per second
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.config.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.config.proxy = Some(ProxyUrl(proxy.into()));
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = Some(user_agent.into());
+        self
+    }
+
+    pub fn overwrite_policy(mut self, overwrite_policy: OverwritePolicy) -> Self {
+        self.overwrite_policy = Some(overwrite_policy);
+        self
+    }
+
+    /// Reports progress somewhere, nothing is shown by default
+    pub fn progress_reporter(mut self, progress: Arc<dyn ProgressReporter>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    pub fn build(self) -> Result<Downloader, RawstErr> {
+        self.config.validate()?;
+        validate_headers(&self.headers)?;
+
+        let overwrite_policy = self.overwrite_policy.unwrap_or(self.config.overwrite_policy);
+        let engine = Engine::new(self.config)?
+            .with_overwrite_policy(overwrite_policy)
+            .with_progress_reporter(self.progress);
+
+        Ok(Downloader {
+            engine,
+            headers: self.headers,
+        })
+    }
+}
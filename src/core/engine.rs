@@ -1,41 +1,175 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::io::IsTerminal;
 use std::collections::HashMap;
 use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use chrono::DateTime;
-use futures::stream::{self, StreamExt};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::DecimalBytes;
+use futures::future::join_all;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
 use iri_string::types::IriString;
+use reqwest::cookie::Jar;
+use reqwest::header::HeaderMap;
+use reqwest::Url;
 use base64::{prelude::BASE64_STANDARD, Engine as Base64Engine};
+use serde::{Serialize, Serializer};
 
-use crate::core::config::Config;
+use crate::core::config::{Config, HttpVersion, OverwritePolicy, SanitizePolicy};
 use crate::core::errors::RawstErr;
-use crate::core::http_handler::HttpHandler;
-use crate::core::task::HttpTask;
-use crate::core::utils::{extract_filename_from_header, extract_filename_from_url, headers_from_file};
-use crate::core::history::HistoryManager;
-use crate::cli::args::InputSource;
+use crate::core::backend::{DownloadBackend, ResourceMeta, Scheme, Segment};
+use crate::core::checksum::{resolve_checksum, ChecksumSource, GivenChecksum};
+use crate::core::ftp::FtpBackend;
+use crate::core::file::FileBackend;
+use crate::core::http_handler::{effective_threads, HeaderChecksum, HttpHandler, SidecarChecksum};
+use crate::core::task::{ByteRange, HttpTask, SegmentStats};
+use crate::core::utils::{basic_auth_value, extract_filename_from_url, headers_from_file, is_directory_output, is_plain_filename, is_unsafe_filename, load_cookie_file, render_filename_template, server_filename, unique_path, unique_path_by, validate_headers};
+use crate::core::history::{HistoryManager, Record};
+use crate::core::hooks::Hooks;
+use crate::core::interrupt::{install_handler, is_interrupted};
+use crate::core::manifest::ResumeManifest;
+use crate::core::metalink::read_metalink;
+use crate::core::output::{confirm, emitter, is_quiet, status, BatchFailure, Event, EventEmitter};
+use crate::core::rate_limiter::RateLimiter;
+use crate::core::progress::{BarReporter, DownloadProgress, JsonReporter, NoProgress, ProgressReporter};
+use crate::cli::args::{parse_checksum, InputSource};
 use crate::cli::args::DownloadArgs;
-use crate::cli::args::ResumeArgs;
-use crate::core::io::{get_cache_sizes, read_links};
+use crate::cli::args::{CleanArgs, ResumeArgs, VerifyArgs};
+use crate::core::io::{clean_cache, create_empty_file, ensure_space, ensure_writable_dir, file_checksum, get_cache_sizes, move_file, parse_links, partial_download_path, save_stream, read_links, read_links_from_stdin, verify_checksum, write_stdout, writes_in_place, Checksum, Output};
 
-pub async fn download(args: DownloadArgs, mut config: Config) -> Result<(), RawstErr> {
+pub async fn download(args: DownloadArgs, mut config: Config) -> Result<Vec<DownloadSummary>, RawstErr> {
     // TODO: Fuse url_download and list_download
     // TODO: Support downloading many elements from each source
     log::trace!("Downloading files ({args:?}, {config:?})");
+    install_handler();
+
     // override the default count in config
-    if args.threads.is_some() {
+    if let Some(threads) = args.threads {
+
+        config.threads = threads.into();
+
+    }
+
+    if let Some(limit_rate) = args.limit_rate {
+
+        config.rate_limit = Some(limit_rate);
+
+    }
+
+    if let Some(max_size) = args.max_size {
+
+        config.max_size = Some(max_size);
+
+    }
+
+    if let Some(proxy) = args.proxy {
+
+        config.proxy = Some(proxy);
+
+    }
+
+    if let Some(ca_cert) = args.ca_cert {
+
+        config.ca_cert = Some(ca_cert);
+
+    }
+
+    if args.insecure {
+
+        config.insecure = true;
+
+    }
+
+    if args.http2 {
+
+        config.http_version = HttpVersion::Http2;
+
+    }
+
+    // Only for this run, the config file isn't written
+    if let Some(dir) = args.dir {
+
+        let dir = std::path::absolute(&dir).map_err(RawstErr::FileError)?;
+        if !args.dry_run {
+            ensure_writable_dir(&dir)?;
+        }
+        config.download_dir = dir;
+
+    }
+
+    if let Some(user_agent) = args.user_agent {
+
+        config.user_agent = Some(user_agent);
+
+    }
+
+    if args.single_file {
+
+        config.single_file = true;
+
+    }
+
+    if args.dynamic_split {
+
+        config.dynamic_split = true;
+
+    }
+
+    if args.decompress {
+
+        config.decompress = true;
+
+    }
+
+    if args.sidecar_checksum {
+
+        config.sidecar_checksums = true;
+
+    }
+
+    if let Some(eta_mode) = args.eta_mode {
+
+        config.eta_mode = eta_mode;
+
+    }
+
+    if let Some(subdir_by) = args.subdir_by {
+
+        config.subdir_by = subdir_by;
+
+    }
 
-        config.threads = args.threads.unwrap().into();
+    let overwrite_policy = if args.overwrite {
+        OverwritePolicy::Overwrite
+    } else if args.no_clobber {
+        OverwritePolicy::Rename
+    } else {
+        config.overwrite_policy
+    };
 
+    let cookie_jar = Jar::default();
+    if let Some(cookie_file) = args.cookie_jar {
+        let count = load_cookie_file(&cookie_file, &cookie_jar)?;
+        log::debug!("Loaded {} cookies from {:?}", count, cookie_file);
     }
 
-    let engine= Engine::new(config);
+    let engine= Engine::new(config)?
+        .with_cookie_jar(Arc::new(cookie_jar))?
+        .with_cookies(args.cookies.into_iter().map(|cookie| cookie.0).collect())
+        .with_overwrite_policy(overwrite_policy)
+        .with_skip_existing(args.skip_existing)
+        .with_conditional(args.conditional)
+        .with_assume_yes(args.yes)
+        .with_stats(args.stats)
+        .with_summaries(true)
+        .with_hooks(Hooks { on_complete: args.on_complete, on_error: args.on_error });
 
-    let additional_headers: HashMap<String, String> = if args.headers_file_path.is_some() {
+    let mut additional_headers: HashMap<String, String> = if let Some(headers_file_path) = args.headers_file_path {
 
-        headers_from_file(args.headers_file_path.unwrap())?
+        headers_from_file(headers_file_path)?
 
     } else {
 
@@ -43,22 +177,68 @@ pub async fn download(args: DownloadArgs, mut config: Config) -> Result<(), Raws
 
     };
 
+    for header in args.headers {
+        additional_headers.insert(header.name, header.value);
+    }
+
+    // Goes into the preflight and every segment along with the other headers
+    let authorization = match (args.user, args.bearer) {
+        (Some(auth), _) => Some(basic_auth_value(&auth.user, &auth.password.0)),
+        (None, Some(token)) => Some(format!("Bearer {}", token.0)),
+        (None, None) => None,
+    };
+    if let Some(authorization) = authorization {
+        additional_headers.retain(|name, _| !name.eq_ignore_ascii_case("authorization"));
+        additional_headers.insert("Authorization".to_string(), authorization);
+    }
+
+    validate_headers(&additional_headers)?;
+
+    if let Some(batch) = args.batch {
+        return engine.process_list_download(batch, additional_headers, args.max_concurrent).await;
+    }
+
+    if let Some(metalink) = args.metalink {
+        return engine.process_metalink_download(metalink, additional_headers).await;
+    }
+
     if let Some(input) = args.input {
 
-        match input {
+        let summaries = match input {
 
-            InputSource::File(file_path) => engine.process_list_download(file_path, additional_headers).await?,
+            InputSource::File(_) if args.dry_run || args.range.is_some() => return Err(RawstErr::InvalidArgs),
+            InputSource::File(file_path) => engine.process_list_download(file_path, additional_headers, args.max_concurrent).await?,
             InputSource::Iris(list_of_iris) => {
                 let iri: IriString = list_of_iris.into_iter().next().ok_or(RawstErr::InvalidArgs)?;
                 let save_as = args.output_file_path.into_iter().next();
 
-                engine.process_url_download(iri, save_as, additional_headers).await?
+                // A directory that doesn't exist yet, as in `-o downloads/`
+                if let Some(dir) = save_as.as_deref().filter(|save_as| is_directory_output(save_as) && !save_as.exists()) {
+                    if !args.dry_run {
+                        ensure_writable_dir(dir)?;
+                    }
+                }
+
+                if args.dry_run {
+                    engine.process_dry_run(iri, args.mirrors, save_as, additional_headers).await?;
+                    Vec::new()
+                } else if let Some(range) = args.range {
+                    vec![engine.process_range_download(iri, args.mirrors, range, save_as, additional_headers, args.checksum).await?]
+                } else if save_as.as_deref() == Some(Path::new("-")) {
+                    if args.checksum.is_some() {
+                        log::warn!("--checksum is ignored when writing to stdout");
+                    }
+
+                    vec![engine.process_stdout_download(iri, args.mirrors, additional_headers).await?]
+                } else {
+                    vec![engine.process_url_download(iri, args.mirrors, save_as, additional_headers, args.checksum).await?]
+                }
 
             }
 
-        }
+        };
 
-        Ok(())
+        Ok(summaries)
 
     } else {
 
@@ -68,241 +248,1743 @@ pub async fn download(args: DownloadArgs, mut config: Config) -> Result<(), Raws
 
 }
 
-pub async fn resume_download(args: ResumeArgs, config: Config) -> Result<(),RawstErr> {
-    let ids= args.download_ids;
-    let mut engine= Engine::new(config);
+pub async fn clean(args: CleanArgs, config: Config) -> Result<(), RawstErr> {
+    let removed = clean_cache(&config, args.older_than, args.dry_run).await?;
+    let bytes = removed.iter().map(|chunk| chunk.bytes).sum();
+
+    emitter().emit(Event::Cleaned { removed, bytes, dry_run: args.dry_run });
+
+    Ok(())
+}
+
+pub async fn verify(args: VerifyArgs, config: Config) -> Result<(), RawstErr> {
+    let path = std::path::absolute(&args.file).map_err(RawstErr::FileError)?;
+
+    let history_manager = HistoryManager::new(config.history_file_path);
+    let record = history_manager.find_by_file(&path)?.ok_or_else(|| RawstErr::NotInHistory(path.clone()))?;
+
+    let checksum = match record.checksum.as_deref().map(parse_checksum) {
+        Some(Ok(checksum)) => checksum,
+        Some(Err(err)) => return Err(RawstErr::HistoryParse(format!("Invalid checksum of {}: {}", path.display(), err))),
+        None => return Err(RawstErr::NoChecksum(path)),
+    };
+
+    log::info!("Verifying {:?} against the download of {} ({})", path, record.iri, record.id);
+    verify_checksum(&path, &checksum).await?;
+
+    emitter().emit(Event::Verified { path, checksum: checksum.to_string() });
+
+    Ok(())
+}
+
+/// Resumes the most recently interrupted download, or all of them with `all`
+///
+/// Uses the resume manifests Ctrl-C leaves in `cache_dir`, so each download
+/// continues with its own segments, headers and history record.
+pub async fn continue_downloads(all: bool, config: Config) -> Result<Vec<DownloadSummary>, RawstErr> {
+    install_handler();
+
+    let manifests = ResumeManifest::list(&config.cache_dir).await?;
+    if manifests.is_empty() {
+        status("No interrupted downloads to continue");
+
+        return Ok(Vec::new());
+    }
+
+    let count = match all {
+        true => manifests.len(),
+        false => 1,
+    };
+    if manifests.len() > count {
+        status(format!("{} more interrupted downloads left, use --continue-all to resume them too", manifests.len() - count));
+    }
+
+    let engine = Engine::new(config)?.with_summaries(true);
+    let mut results = Vec::with_capacity(count);
+    for manifest in manifests.into_iter().take(count) {
+        status(format!("Continuing {} from {}", manifest.filename.display(), manifest.url));
+
+        let url = manifest.url.to_string();
+        // The record keeps the checksum the download was started with
+        let result = match engine.history_manager.get_record(&manifest.id) {
+            Ok(record) => {
+                let checksum = record.as_ref().and_then(stored_checksum);
+                engine.clone().process_url_download(manifest.url, Vec::new(), None, manifest.headers, checksum).await
+            }
+            Err(err) => Err(err),
+        };
+        results.push((url, result));
+    }
+
+    match all {
+        true => batch_outcome(results),
+        false => results.into_iter().map(|(_, result)| result).collect(),
+    }
+}
+
+pub async fn resume_download(args: ResumeArgs, config: Config) -> Result<Vec<DownloadSummary>, RawstErr> {
+    install_handler();
+    let mut engine = Engine::new(config)?.with_summaries(true);
+
+    let mut summaries = Vec::with_capacity(args.download_ids.len());
+    for id in args.download_ids {
+        summaries.extend(engine.process_resume_request(id).await?);
+    }
+
+    Ok(summaries)
+}
+
+/// What a download would do, for `--dry-run`
+///
+/// Emitted as a `plan` event with `--json`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DownloadPlan {
+    pub url: String,
+    pub mirrors: Vec<String>,
+    pub file: PathBuf,
+    /// `None` when the server doesn't say
+    pub bytes: Option<u64>,
+    pub supports_ranges: bool,
+    pub threads: usize,
+    pub destination: PathBuf,
+}
+
+/// How a completed download was split and how fast its segments went, for `--stats`
+///
+/// Emitted as a `stats` event with `--json`, with durations as `seconds`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DownloadStats {
+    pub url: String,
+    pub segments: Vec<SegmentReport>,
+    #[serde(rename = "seconds", serialize_with = "serialize_seconds")]
+    pub wall_time: Duration,
+    /// Bytes of this run, without the ones of earlier attempts
+    pub bytes: u64,
+    pub avg_bps: f64,
+}
 
-    if ids.len() > 1 {
-        for id in ids {
-            engine.process_resume_request(id).await?
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SegmentReport {
+    pub index: usize,
+    /// Inclusive like HTTP ranges, eg. `0-1048575`
+    pub range: String,
+    pub bytes: u64,
+    #[serde(rename = "seconds", serialize_with = "serialize_seconds")]
+    pub elapsed: Duration,
+    pub avg_bps: f64,
+}
 
+impl DownloadStats {
+    // Downloads in a single stream don't track segments, they're shown as one taking `download_time`
+    fn of(task: &HttpTask, resumed_at: u64, download_time: Duration, wall_time: Duration) -> Self {
+        let mut segments = task.segment_stats.lock().unwrap().clone();
+        if segments.is_empty() {
+            let downloaded = task.total_downloaded.load(Ordering::SeqCst);
+            segments.push(SegmentStats {
+                index: 0,
+                range: ByteRange { start: resumed_at, end: downloaded.checked_sub(1) },
+                bytes: downloaded.saturating_sub(resumed_at),
+                elapsed: download_time,
+            });
         }
+        segments.sort_by_key(|segment| segment.index);
 
-        Ok(())
+        let bytes = segments.iter().map(|segment| segment.bytes).sum();
+        let segments = segments
+            .iter()
+            .map(|segment| SegmentReport {
+                index: segment.index,
+                range: segment.range.to_string(),
+                bytes: segment.bytes,
+                elapsed: segment.elapsed,
+                avg_bps: segment.bytes_per_second(),
+            })
+            .collect();
+
+        DownloadStats {
+            url: task.iri.to_string(),
+            segments,
+            wall_time,
+            bytes,
+            avg_bps: average_speed(bytes, wall_time),
+        }
+    }
+}
+
+// Output paths of the downloads in progress, with the URLs saving to each
+type ClaimedOutputs = Arc<Mutex<HashMap<PathBuf, Vec<String>>>>;
+
+// Gives the output back once the download is over, whichever way it ended
+struct OutputClaim {
+    claimed: ClaimedOutputs,
+    path: PathBuf,
+}
+
+impl Drop for OutputClaim {
+    fn drop(&mut self) {
+        let mut claimed = self.claimed.lock().unwrap();
+
+        if let Some(urls) = claimed.get_mut(&self.path) {
+            urls.pop();
+            if urls.is_empty() {
+                claimed.remove(&self.path);
+            }
+        }
     }
-    else {
-        let id= ids.iter().next().unwrap().to_string();
-        engine.process_resume_request(id).await
+}
+
+/// What a download left on disk
+///
+/// Emitted as a `downloaded` event with `--json`, with `duration` as `seconds`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DownloadSummary {
+    pub url: String,
+    /// `-` for downloads written to stdout
+    pub path: PathBuf,
+    /// Size of the file, including the bytes of earlier attempts when resumed
+    pub bytes: u64,
+    #[serde(rename = "seconds", serialize_with = "serialize_seconds")]
+    pub duration: Duration,
+    /// Bytes per second of this run, without the ones of earlier attempts
+    pub avg_bps: f64,
+    /// Segments downloaded at once, 0 when nothing had to be downloaded
+    pub segments: usize,
+    pub checksum: Option<String>,
+}
 
+impl DownloadSummary {
+    // For downloads skipped because history already has them
+    fn of_record(record: &Record, duration: Duration) -> Self {
+        DownloadSummary {
+            url: record.iri.to_string(),
+            path: record.file_path(),
+            bytes: record.file_size,
+            duration,
+            avg_bps: 0.0,
+            segments: 0,
+            checksum: record.checksum.clone(),
+        }
     }
+}
+
+fn serialize_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
 
+// Bytes per second of `bytes` downloaded in `duration`
+fn average_speed(bytes: u64, duration: Duration) -> f64 {
+    bytes as f64 / duration.as_secs_f64().max(f64::EPSILON)
 }
 
+#[derive(Clone)]
 pub struct Engine {
     config: Config,
     http_handler: HttpHandler,
+    ftp: FtpBackend,
+    file: FileBackend,
+    // One budget for every download of the engine and its clones, `--limit-rate` caps a whole batch
+    rate_limiter: Option<Arc<RateLimiter>>,
     history_manager: HistoryManager,
-    multi_bar: MultiProgress,
+    progress: Arc<dyn ProgressReporter>,
+    overwrite_policy: OverwritePolicy,
+    skip_existing: bool,
+    conditional: bool,
+    assume_yes: bool,
+    stats: bool,
+    summaries: bool,
+    hooks: Hooks,
+    // Shared with the clones of a batch
+    claimed_outputs: ClaimedOutputs,
+    cookie_jar: Arc<Jar>,
+    // `name=value` pairs sent to every URL on top of the jar
+    cookies: Vec<String>,
+    // Size the next download must have, given by a metalink
+    expected_size: Option<u64>,
 }
 
 impl Engine {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self, RawstErr> {
+
+        if config.insecure {
+            log::warn!("TLS certificates are not verified");
+            status("Warning!: TLS certificates are not verified, anyone on the network can read and change the downloads");
+        }
+
+        // Better found now than after the first segment was downloaded
+        if let Some(temp_dir) = &config.temp_dir {
+            ensure_writable_dir(temp_dir)
+                .map_err(|err| RawstErr::ConfigParse(format!("temp_dir {:?} can't be written to: {}", temp_dir, err)))?;
+        }
 
         let history_manager= HistoryManager::new(config.history_file_path.clone());
+        let cookie_jar = Arc::new(Jar::default());
+        let rate_limiter = config.rate_limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+        let http_handler = HttpHandler::with_cookies(&config, cookie_jar.clone())?.with_rate_limiter(rate_limiter.clone());
+        let ftp = FtpBackend::new(&config, rate_limiter.clone());
+        let file = FileBackend::new(rate_limiter.clone());
+        let progress: Arc<dyn ProgressReporter> = match (emitter(), is_quiet()) {
+            (EventEmitter::Json, _) => Arc::new(JsonReporter),
+            (EventEmitter::Human, true) => Arc::new(NoProgress),
+            (EventEmitter::Human, false) => Arc::new(BarReporter::with_eta_mode(config.eta_mode)),
+        };
+
+        let overwrite_policy = config.overwrite_policy;
 
-        Engine {
+        Ok(Engine {
             config,
-            http_handler: HttpHandler::new(),
+            http_handler,
+            ftp,
+            file,
+            rate_limiter,
             history_manager,
-            multi_bar: MultiProgress::new(),
-        }
+            progress,
+            overwrite_policy,
+            skip_existing: false,
+            conditional: false,
+            assume_yes: false,
+            stats: false,
+            summaries: false,
+            hooks: Hooks::default(),
+            claimed_outputs: ClaimedOutputs::default(),
+            cookie_jar,
+            cookies: Vec::new(),
+            expected_size: None,
+        })
     }
 
-    pub async fn process_url_download(mut self, iri: IriString, save_as: Option<PathBuf>, additional_headers: HashMap<String, String>) -> Result<(), RawstErr> {
+    /// Starts from the cookies in `cookie_jar` instead of an empty jar
+    pub fn with_cookie_jar(mut self, cookie_jar: Arc<Jar>) -> Result<Self, RawstErr> {
+        self.http_handler = HttpHandler::with_cookies(&self.config, cookie_jar.clone())?.with_rate_limiter(self.rate_limiter.clone());
+        self.cookie_jar = cookie_jar;
+        Ok(self)
+    }
 
-        let http_task = self.create_http_task(iri, (&save_as).into(), &additional_headers).await?;
+    /// Sends `name=value` cookies to every URL downloaded
+    pub fn with_cookies(mut self, cookies: Vec<String>) -> Self {
+        self.cookies = cookies;
+        self
+    }
 
-        let encoded_timestamp_as_id = BASE64_STANDARD.encode(http_task.timestamp.timestamp().to_be_bytes());
-    
-        self.history_manager.add_record(&http_task, &self.config, encoded_timestamp_as_id.clone())?;
-    
-        self.http_download(http_task).await?;
-    
-        self.history_manager.update_record(encoded_timestamp_as_id)?;
-    
-        Ok(())
+    /// Fails the download unless the file ends up `expected_size` bytes long
+    pub fn with_expected_size(mut self, expected_size: Option<u64>) -> Self {
+        self.expected_size = expected_size;
+        self
     }
 
-    pub async fn process_list_download(mut self, file_path: PathBuf, additional_headers: HashMap<String, String>) -> Result<(), RawstErr> {
-        self.config.threads = 1;
-        
-        let link_string = read_links(&file_path).await?;
-    
-        let mut tasks: HashMap<String, HttpTask> = HashMap::new();
-    
-        let url_list = link_string.split("\n").collect::<Vec<&str>>();
-        for (index, line) in url_list.iter().enumerate() {
-            let iri = line
-                .trim()
-                .parse::<IriString>()
-                .map_err(|_| RawstErr::InvalidArgs)?;
-    
-            let http_task = self.create_http_task(iri, None, &additional_headers).await?;
-    
-            // Adding index number to distinguish between each id of each task
-            let encoded_timestamp_as_id =
-                BASE64_STANDARD.encode(http_task.timestamp.timestamp().to_be_bytes()) + &index.to_string();
-    
-            self.history_manager.add_record(&http_task, &self.config, encoded_timestamp_as_id.clone())?;
+    /// Reports progress somewhere else than terminal bars
+    pub fn with_progress_reporter(mut self, progress: Arc<dyn ProgressReporter>) -> Self {
+        self.progress = progress;
+        self
+    }
 
-            tasks.insert(encoded_timestamp_as_id, http_task);
-    
-        }
+    pub fn with_skip_existing(mut self, skip_existing: bool) -> Self {
+        self.skip_existing = skip_existing;
+        self
+    }
 
-        let val: Vec<HttpTask> = tasks.clone().into_values().collect();
-    
-        self.list_http_download(val).await?;
-    
-        for id in tasks.keys() {
-            self.history_manager.update_record(id.to_owned())?;
+    /// Skips URLs whose last completed download is still the server's version
+    pub fn with_conditional(mut self, conditional: bool) -> Self {
+        self.conditional = conditional;
+        self
+    }
+
+    /// Starts downloads larger than `confirm_above_bytes` without asking
+    pub fn with_assume_yes(mut self, assume_yes: bool) -> Self {
+        self.assume_yes = assume_yes;
+        self
+    }
+
+    pub fn with_overwrite_policy(mut self, overwrite_policy: OverwritePolicy) -> Self {
+        self.overwrite_policy = overwrite_policy;
+        self
+    }
+
+    /// Prints how each download was split and how fast its segments went
+    pub fn with_stats(mut self, stats: bool) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Reports each completed download, see [`Event::Downloaded`]
+    pub fn with_summaries(mut self, summaries: bool) -> Self {
+        self.summaries = summaries;
+        self
+    }
+
+    /// Runs commands after each download completes or fails
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    // What `task` is verified against, from the first of its sources that has a checksum
+    async fn checksum_of(&self, task: &HttpTask, given: Option<Checksum>) -> Result<Option<Checksum>, RawstErr> {
+        let given = GivenChecksum(given);
+        let sidecar = SidecarChecksum(&self.http_handler);
+
+        let mut sources: Vec<&dyn ChecksumSource> = vec![&given];
+        if self.config.sidecar_checksums {
+            sources.push(&sidecar);
         }
-    
-        Ok(())
+        sources.push(&HeaderChecksum);
+
+        resolve_checksum(&sources, task).await
     }
 
-    pub async fn process_resume_request(&mut self, id: String) -> Result<(), RawstErr> {
-        log::trace!("Resuming download (id:{:?}, config:{:?})", id, self.config);
-        let record = if id == "auto" {
-            self.history_manager.get_recent_pending()?
-        } else {
-            self.history_manager.get_record(&id)?
+    /// Asks before a download larger than `confirm_above_bytes` starts, unless `--yes` was given
+    async fn confirm_size(&self, http_task: &HttpTask) -> Result<(), RawstErr> {
+        let (Some(threshold), Some(bytes)) = (self.config.confirm_above_bytes, http_task.known_length()) else {
+            return Ok(());
         };
-    
-        match record {
-            Some(data) => {
-                // notice: I can also get total file size by getting content length through http_task object
-                if data.status == "Pending" {
-                    self.config.threads = data.threads_used;
-                    self.config.download_dir = data.file_location;
-
-                    let file_name = PathBuf::from(&data.file_name.file_stem().unwrap());
+        if bytes <= threshold || self.assume_yes {
+            return Ok(());
+        }
 
-                    let mut http_task = self
-                        .create_http_task(data.iri.clone(), Some(&file_name), &data.headers)
-                        .await?;
+        // Waiting for an answer nobody can give would hang scripts and cron jobs
+        if !std::io::stdin().is_terminal() {
+            return Err(RawstErr::ConfirmationNeeded { file: http_task.filename.clone(), bytes });
+        }
 
-                    http_task.timestamp = DateTime::from_str(data.timestamp.as_str()).unwrap();
-    
-                    let cache_sizes =
-                        get_cache_sizes(http_task.hashed_file_name(), data.threads_used, self.config.clone())?;
-    
-                    http_task.calculate_x_offsets(&cache_sizes);
-    
-                    http_task
-                        .total_downloaded
-                        .fetch_add(cache_sizes.iter().sum::<u64>(), Ordering::SeqCst);
-    
-                    self.http_download(http_task).await?;
-    
-                    self.history_manager.update_record(data.id)?
-                } else {
-                    println!("The file is already downloaded");
-    
-                    return Ok(());
-                }
-            }
-            None => {
-                println!("Record with id {:?} not found", id);
-    
-                return Ok(());
-            }
+        let question = format!("This will download {} to {}. Continue?", DecimalBytes(bytes), http_task.filename.display());
+        if !confirm(question).await? {
+            return Err(RawstErr::Declined(http_task.filename.clone()));
         }
-    
+
         Ok(())
     }
 
-    pub async fn http_download(&self, task: HttpTask) -> Result<(), RawstErr> {
-        log::trace!("Starting HTTP download (task:{task:?})");
-        let file_name_str = task.filename.display().to_string();
+    pub async fn process_url_download(
+        mut self,
+        iri: IriString,
+        mirrors: Vec<IriString>,
+        save_as: Option<PathBuf>,
+        additional_headers: HashMap<String, String>,
+        checksum: Option<Checksum>
+    ) -> Result<DownloadSummary, RawstErr> {
 
-        let progressbar = self
-            .multi_bar
-            .add(ProgressBar::new(task.content_length()).with_message(file_name_str));
+        self.download_url(iri, mirrors, save_as, &additional_headers, checksum, 0).await
+    }
 
-        progressbar.set_style(ProgressStyle::with_template("{msg} | {bytes}/{total_bytes} | [{wide_bar:.green/white}] | {eta} | [{decimal_bytes_per_sec}]")
-        .unwrap()
-        .progress_chars("=>_"));
+    /// Downloads every file of a metalink, trying its URLs in order of preference
+    pub async fn process_metalink_download(
+        self,
+        path: PathBuf,
+        additional_headers: HashMap<String, String>,
+    ) -> Result<Vec<DownloadSummary>, RawstErr> {
+        let files = read_metalink(&path).await?;
+        let mut summaries = Vec::with_capacity(files.len());
 
-        progressbar.set_position(task.total_downloaded.load(Ordering::SeqCst));
-        progressbar.reset_eta();
+        for file in files {
+            log::info!("Downloading {} from {} URLs", file.name, file.urls.len());
+            let save_as = file.file_name().map(|name| self.config.download_dir.join(name));
 
-        match self.config.threads {
-            1 => {
-                self.http_handler
-                    .sequential_download(&task, &progressbar, &self.config)
-                    .await?
-            }
-            _ => {
-                self.http_handler
-                    .concurrent_download(&task, &progressbar, &self.config)
-                    .await?
-            }
+            let mut urls = file.urls.into_iter();
+            // Files without URLs are rejected while parsing
+            let iri = urls.next().unwrap();
+            // Without a hash the size is all there is to check, a file of another size is removed
+            let summary = self.clone()
+                .with_expected_size(file.size)
+                .process_url_download(iri, urls.collect(), save_as, additional_headers.clone(), file.checksum)
+                .await?;
+
+            summaries.push(summary);
+        }
+
+        Ok(summaries)
+    }
+
+    /// Prints the plan for downloading `iri` after the preflight, without touching the filesystem
+    pub async fn process_dry_run(
+        mut self,
+        iri: IriString,
+        mirrors: Vec<IriString>,
+        save_as: Option<PathBuf>,
+        additional_headers: HashMap<String, String>,
+    ) -> Result<(), RawstErr> {
+        for url in std::iter::once(&iri).chain(&mirrors) {
+            self.add_cookies(url);
         }
 
-        progressbar.finish();
+        let urls = std::iter::once(iri).chain(mirrors).collect();
+        let mut task = self.download_with_mirrors(urls, save_as.as_ref(), &additional_headers, 0).await?;
+        self.resolve_collision(&mut task, self.overwrite_policy)?;
+
+        let meta = task.meta();
+        emitter().emit(Event::Plan(DownloadPlan {
+            url: task.iri.to_string(),
+            mirrors: task.mirrors.iter().map(|mirror| mirror.to_string()).collect(),
+            file: task.filename.clone(),
+            bytes: meta.length,
+            supports_ranges: meta.supports_ranges,
+            threads: self.config.threads,
+            destination: self.config.download_dir.join(&task.filename),
+        }));
 
         Ok(())
     }
 
-    pub async fn list_http_download(&self, tasks: Vec<HttpTask>) -> Result<(), RawstErr> {
-        let http_download_tasks = stream::iter((0..tasks.len()).map(|i| {
-            let threaded_task = tasks[i].clone();
+    /// Streams a download to stdout instead of saving it
+    ///
+    /// Always uses a single stream so the bytes come out in order. Nothing is
+    /// recorded in history as there's no file to resume.
+    pub async fn process_stdout_download(
+        mut self,
+        iri: IriString,
+        mirrors: Vec<IriString>,
+        additional_headers: HashMap<String, String>,
+    ) -> Result<DownloadSummary, RawstErr> {
+        let started = Instant::now();
+        self.config.threads = 1;
+
+        for url in std::iter::once(&iri).chain(&mirrors) {
+            self.add_cookies(url);
+        }
 
-            async move {
-                self.http_download(threaded_task).await?;
+        let urls = std::iter::once(iri).chain(mirrors).collect();
+        let task = self.download_with_mirrors(urls, None, &additional_headers, 0).await?;
 
-                Ok::<_, RawstErr>(())
+        // Bars are drawn on stderr and messages follow them there, nothing gets mixed with the data
+        let progress = self.progress.add(task.iri.as_str(), &task.filename.display().to_string(), task.known_length(), 0);
+        let result = match Scheme::of(&task.iri)? {
+            Scheme::Http => {
+                self.http_handler
+                    .sequential_download(&task, progress.as_ref(), &self.config, &Output::Stdout)
+                    .await
             }
-        }));
+            Scheme::Ftp => self.stream_to_stdout(&self.ftp, &task, progress.as_ref()).await,
+            Scheme::File => self.stream_to_stdout(&self.file, &task, progress.as_ref()).await,
+        };
 
-        http_download_tasks
-            .buffer_unordered(tasks.len())
-            .collect::<Vec<_>>()
-            .await;
+        match &result {
+            Ok(()) => progress.finish(),
+            Err(err) => progress.fail(err),
+        }
+        result?;
 
-        Ok(())
+        // Not reported, the report would end up in the data
+        Ok(self.summary_of(&task, PathBuf::from("-"), 0, started.elapsed(), None))
     }
 
-    pub async fn create_http_task(
-        &mut self,
+    /// Downloads only the bytes of `range`, to a file or to stdout with `-o -`
+    pub async fn process_range_download(
+        mut self,
         iri: IriString,
-        save_as: Option<&PathBuf>,
-        additional_headers: &HashMap<String, String>
-    ) -> Result<HttpTask, RawstErr> {
-        log::trace!("Creating HTTP download task (iri:{iri:?}, save_as:{save_as:?})");
-        let cached_headers = self.http_handler.cache_headers(&iri, additional_headers).await?;
+        mirrors: Vec<IriString>,
+        range: ByteRange,
+        save_as: Option<PathBuf>,
+        additional_headers: HashMap<String, String>,
+        checksum: Option<Checksum>,
+    ) -> Result<DownloadSummary, RawstErr> {
+        let started = Instant::now();
+        self.config.threads = 1;
+
+        for url in std::iter::once(&iri).chain(&mirrors) {
+            self.add_cookies(url);
+        }
+
+        let to_stdout = save_as.as_deref() == Some(Path::new("-"));
+        let save_as = save_as.filter(|_| !to_stdout);
+
+        let urls = std::iter::once(iri).chain(mirrors).collect();
+        let mut task = self.download_with_mirrors(urls, save_as.as_ref(), &additional_headers, 0).await?;
+        if !to_stdout {
+            self.resolve_collision(&mut task, self.overwrite_policy)?;
+        }
 
-        let mut filename = match extract_filename_from_header(&cached_headers) {
-            Some(result) => result,
-            None => extract_filename_from_url(&iri),
+        // Without a Content-Length the range can only be checked by what arrives
+        let end = match (range.end, task.known_length()) {
+            (end, Some(length)) if range.start >= length || end.is_some_and(|end| end >= length) => {
+                return Err(RawstErr::RangeOutOfBounds { range: range.to_string(), length });
+            }
+            (Some(end), _) => Some(end),
+            (None, length) => length.map(|length| length - 1),
         };
+        let length = end.map(|end| end + 1 - range.start);
 
-        if let Some(save_as) = save_as {
-            let extension = filename.extension().unwrap();
-            let output_path = save_as.parent().unwrap();
-            if output_path.exists() {
-                self.config.download_dir = output_path.to_path_buf();
+        let progress = self.progress.add(task.iri.as_str(), &task.filename.display().to_string(), length, 0);
+        let result = async {
+            let segment = match Scheme::of(&task.iri)? {
+                Scheme::Http => self.http_handler.segment(&task.iri, &task.additional_headers, range.start, end).await?,
+                Scheme::Ftp => self.ftp.segment(&task.iri, &task.additional_headers, range.start, end).await?,
+                Scheme::File => self.file.segment(&task.iri, &task.additional_headers, range.start, end).await?,
+            };
 
+            match to_stdout {
+                true => write_stdout(&task, segment, false, progress.as_ref(), self.rate_limiter.as_deref(), self.config.buffer_size).await,
+                false => self.save_range(&task, segment, length, progress.as_ref(), checksum.clone()).await,
             }
-            
-            let mut new_filename = PathBuf::from(save_as.file_name().unwrap());
-            new_filename.add_extension(extension);
-            filename = new_filename;
-            assert!(filename.is_relative());
+        }.await;
+
+        match &result {
+            Ok(()) => progress.finish(),
+            Err(err) => progress.fail(err),
         }
+        result?;
 
-        let mut task = HttpTask::new(iri, filename, cached_headers, additional_headers.to_owned());
+        let path = match to_stdout {
+            true => PathBuf::from("-"),
+            false => self.config.download_dir.join(&task.filename),
+        };
+        let summary = self.summary_of(&task, path, 0, started.elapsed(), checksum.map(|checksum| checksum.to_string()));
+        if self.summaries && !to_stdout {
+            emitter().emit(Event::Downloaded(summary.clone()));
+        }
 
-        // checks if the server allows to receive byte ranges for concurrent download
-        // otherwise uses single thread
-        if self.config.threads > 1 && !task.allows_partial_content() {
-            println!("Warning!: Server doesn't allow partial content, sequentially downloading..");
-            self.config.threads = 1
+        Ok(summary)
+    }
 
+    // Summary of a finished `task`, `resumed_at` bytes were there before this run
+    fn summary_of(&self, task: &HttpTask, path: PathBuf, resumed_at: u64, duration: Duration, checksum: Option<String>) -> DownloadSummary {
+        let bytes = task.total_downloaded.load(Ordering::SeqCst);
+        // Single streams don't record their segment
+        let segments = task.segment_stats.lock().unwrap().len().max(1);
+
+        DownloadSummary {
+            url: task.iri.to_string(),
+            path,
+            bytes,
+            duration,
+            avg_bps: average_speed(bytes.saturating_sub(resumed_at), duration),
+            segments,
+            checksum,
         }
+    }
 
-        task.calculate_chunks(self.config.threads as u64);
+    // Saves a range to the output of `task`, deleting what was written if it isn't all there
+    async fn save_range(
+        &self,
+        task: &HttpTask,
+        segment: Segment,
+        length: Option<u64>,
+        progress: &dyn DownloadProgress,
+        checksum: Option<Checksum>,
+    ) -> Result<(), RawstErr> {
+        let partial_path = partial_download_path(task, &self.config.download_dir);
+        tokio::fs::create_dir_all(&self.config.download_dir).await.map_err(RawstErr::FileError)?;
 
-        Ok(task)
+        let result = async {
+            save_stream(task, segment, false, progress, &partial_path, self.rate_limiter.as_deref(), self.config.buffer_size).await?;
+
+            let written = task.total_downloaded.load(Ordering::SeqCst);
+            if let Some(expected) = length.filter(|&expected| expected != written) {
+                return Err(RawstErr::SizeMismatch { expected, actual: written });
+            }
+
+            match &checksum {
+                Some(checksum) => verify_checksum(&partial_path, checksum).await,
+                None => Ok(()),
+            }
+        }.await;
+
+        if let Err(err) = result {
+            // A partial range can't be resumed, nothing records where it started
+            let _ = tokio::fs::remove_file(&partial_path).await;
+
+            return Err(err);
+        }
+
+        move_file(&partial_path, &self.config.download_dir.join(&task.filename)).await
+    }
+
+    // Streams the whole of `task` through `backend`, which has no stdout mode of its own
+    async fn stream_to_stdout<B: DownloadBackend>(&self, backend: &B, task: &HttpTask, progress: &dyn DownloadProgress) -> Result<(), RawstErr> {
+        let segment = backend.segment(&task.iri, &task.additional_headers, 0, None).await?;
+
+        write_stdout(task, segment, false, progress, self.rate_limiter.as_deref(), self.config.buffer_size).await
+    }
+
+    // Downloads a single file and runs the hook for how it went
+    //
+    // `index` is the 1-based position in a batch, or 0 outside of one.
+    async fn download_url(
+        &mut self,
+        iri: IriString,
+        mirrors: Vec<IriString>,
+        save_as: Option<PathBuf>,
+        additional_headers: &HashMap<String, String>,
+        checksum: Option<Checksum>,
+        index: usize
+    ) -> Result<DownloadSummary, RawstErr> {
+        let url = iri.to_string();
+        // Only `-o` tells where a download going wrong in its preflight would have gone
+        let requested_path = save_as.clone().unwrap_or_default();
+
+        let result = self.record_download(iri, mirrors, save_as, additional_headers, checksum, index).await;
+
+        match &result {
+            Ok(summary) => {
+                if self.summaries {
+                    emitter().emit(Event::Downloaded(summary.clone()));
+                }
+                self.hooks.completed(&summary.path, &url).await
+            }
+            Err(err) => self.hooks.failed(&requested_path, &url, err).await,
+        }
+
+        result
+    }
+
+    // Downloads a single file and records it in history
+    async fn record_download(
+        &mut self,
+        iri: IriString,
+        mirrors: Vec<IriString>,
+        save_as: Option<PathBuf>,
+        additional_headers: &HashMap<String, String>,
+        checksum: Option<Checksum>,
+        index: usize
+    ) -> Result<DownloadSummary, RawstErr> {
+        let started = Instant::now();
+
+        // Downloads waiting for their turn in a batch don't start after Ctrl-C
+        if is_interrupted() {
+            return Err(RawstErr::Interrupted);
+        }
+
+        if self.skip_existing {
+            if let Some(record) = self.history_manager.find_completed(iri.as_str())? {
+                if is_intact(&record).await {
+                    log::info!("{} was already downloaded to {:?}, skipping", iri, record.file_path());
+                    status(format!("{} already downloaded", record.file_path().display()));
+
+                    return Ok(DownloadSummary::of_record(&record, started.elapsed()));
+                }
+            }
+        }
+
+        for url in std::iter::once(&iri).chain(&mirrors) {
+            self.add_cookies(url);
+        }
+
+        let mut overwrite_policy = self.overwrite_policy;
+        if self.conditional && matches!(Scheme::of(&iri), Ok(Scheme::Http)) {
+            if let Some(record) = self.history_manager.find_completed(iri.as_str())? {
+                if record.file_path().exists() {
+                    let modified = self.http_handler
+                        .is_modified(&iri, additional_headers, record.etag.as_deref(), record.last_modified.as_deref())
+                        .await?;
+
+                    if !modified {
+                        log::info!("{} is unchanged since it was downloaded to {:?}, skipping", iri, record.file_path());
+                        status(format!("{} not modified", record.file_path().display()));
+
+                        return Ok(DownloadSummary::of_record(&record, started.elapsed()));
+                    }
+
+                    // The newer version replaces the one downloaded before
+                    if overwrite_policy == OverwritePolicy::Error {
+                        overwrite_policy = OverwritePolicy::Overwrite;
+                    }
+                }
+            }
+        }
+
+        // Held until the download is over, so the others running alongside don't pick its output
+        let (http_task, encoded_timestamp_as_id, _claim) = match self.resume_interrupted(&iri).await? {
+            Some((mut http_task, id)) => {
+                http_task.checksum = self.checksum_of(&http_task, checksum.clone()).await?;
+                // The manifest knows the file by its name, it can't be renamed
+                let claim = self.claim_output(&mut http_task, OverwritePolicy::Overwrite)?;
+                (http_task, id, claim)
+            }
+            None => {
+                let urls = std::iter::once(iri).chain(mirrors).collect();
+                let mut http_task = self.download_with_mirrors(urls, (&save_as).into(), additional_headers, index).await?;
+                http_task.checksum = self.checksum_of(&http_task, checksum.clone()).await?;
+                self.resolve_collision(&mut http_task, overwrite_policy)?;
+                let claim = self.claim_output(&mut http_task, overwrite_policy)?;
+                self.confirm_size(&http_task).await?;
+
+                let mut encoded_timestamp_as_id = BASE64_STANDARD.encode(http_task.timestamp.timestamp().to_be_bytes());
+                if index > 0 {
+                    // Adding index number to distinguish between each id of tasks started together
+                    encoded_timestamp_as_id += &index.to_string();
+                }
+
+                let encoded_timestamp_as_id = self.history_manager.add_record(&http_task, &self.config, encoded_timestamp_as_id)?;
+
+                (http_task, encoded_timestamp_as_id, claim)
+            }
+        };
+    
+        let output_path = self.config.download_dir.join(&http_task.filename);
+        // Shares the progress counters, so it knows how far the download got
+        let progress = http_task.clone();
+        let resumed_at = progress.total_downloaded.load(Ordering::SeqCst);
+        let download_started = Instant::now();
+        let result = self.http_download(http_task).await;
+        let download_time = download_started.elapsed();
+
+        match &result {
+            Ok(()) => ResumeManifest::remove(&self.config.cache_dir, &progress.iri).await?,
+            Err(RawstErr::Interrupted) => {
+                ResumeManifest::new(&progress, &self.config, encoded_timestamp_as_id.clone())
+                    .save(&self.config.cache_dir)
+                    .await?;
+                status(format!("Progress of {} saved, download it again to resume", progress.filename.display()));
+            }
+            // The partial file is gone, there's nothing left to resume
+            Err(RawstErr::SizeMismatch { .. } | RawstErr::ResourceChanged(_)) => ResumeManifest::remove(&self.config.cache_dir, &progress.iri).await?,
+            // Continuing a runaway download would only make it larger
+            Err(RawstErr::SizeLimitExceeded { .. }) => {
+                ResumeManifest::remove(&self.config.cache_dir, &progress.iri).await?;
+                match tokio::fs::remove_file(partial_download_path(&progress, &self.config.download_dir)).await {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(RawstErr::FileError(err)),
+                    _ => {}
+                }
+            }
+            // Otherwise only the chunk files would tell how far each segment got
+            Err(_) if writes_in_place(&progress, &self.config) => {
+                ResumeManifest::new(&progress, &self.config, encoded_timestamp_as_id.clone())
+                    .save(&self.config.cache_dir)
+                    .await?;
+            }
+            Err(_) => {}
+        }
+
+        let final_checksum = saved_checksum(&result, &progress, &output_path).await;
+    
+        self.history_manager.update_record(encoded_timestamp_as_id, result.is_ok(), final_checksum.clone())?;
+        result?;
+
+        if self.stats {
+            emitter().emit(Event::Stats(DownloadStats::of(&progress, resumed_at, download_time, started.elapsed())));
+        }
+
+        Ok(self.summary_of(&progress, output_path, resumed_at, started.elapsed(), final_checksum))
+    }
+
+    // Puts the `--cookie` values in the jar for the host of `iri`
+    fn add_cookies(&self, iri: &IriString) {
+        let Ok(url) = Url::parse(iri.as_str()) else {
+            return;
+        };
+
+        // Without a path they'd only go to the directory of the URL, not to redirects
+        for cookie in &self.cookies {
+            self.cookie_jar.add_cookie_str(&format!("{}; Path=/", cookie), &url);
+        }
+    }
+
+    pub async fn process_list_download(
+        self,
+        file_path: PathBuf,
+        additional_headers: HashMap<String, String>,
+        max_concurrent: usize
+    ) -> Result<Vec<DownloadSummary>, RawstErr> {
+        let link_string = if file_path == Path::new("-") {
+            read_links_from_stdin().await?
+        } else {
+            read_links(&file_path).await?
+        };
+        let urls = parse_links(&link_string);
+
+        let results = self.download_batch(urls, max_concurrent, &additional_headers).await;
+
+        batch_outcome(results)
+    }
+
+    /// Downloads `urls` with at most `max_concurrent` running at a time
+    ///
+    /// Each download is split in segments on its own. Returns the result of
+    /// every URL in the order given.
+    pub async fn download_batch(
+        &self,
+        urls: Vec<String>,
+        max_concurrent: usize,
+        additional_headers: &HashMap<String, String>
+    ) -> Vec<(String, Result<DownloadSummary, RawstErr>)> {
+        let semaphore = Semaphore::new(max_concurrent.max(1));
+
+        let downloads = urls.into_iter().enumerate().map(|(i, url)| {
+            // Each download settles its own thread count
+            let mut engine = self.clone();
+            let semaphore = &semaphore;
+
+            async move {
+                // The semaphore is never closed
+                let _permit = semaphore.acquire().await.unwrap();
+
+                let result = match url.parse::<IriString>() {
+                    Ok(iri) => engine.download_url(iri, Vec::new(), None, additional_headers, None, i + 1).await,
+                    Err(_) => Err(RawstErr::InvalidArgs),
+                };
+
+                (url, result)
+            }
+        });
+
+        join_all(downloads).await
+    }
+
+    // Recreates the task of a download started earlier, counting the chunks already on disk
+    async fn resume_task(
+        &mut self,
+        iri: &IriString,
+        file_name: &Path,
+        download_dir: PathBuf,
+        threads: usize,
+        timestamp: &str,
+        headers: &HashMap<String, String>
+    ) -> Result<HttpTask, RawstErr> {
+        // notice: I can also get total file size by getting content length through http_task object
+        self.config.threads = threads;
+        self.config.download_dir = download_dir;
+
+        let file_name = PathBuf::from(file_name.file_name().unwrap());
+
+        let mut http_task = self
+            .create_http_task(iri.clone(), Some(&file_name), headers, 0)
+            .await?;
+
+        http_task.timestamp = DateTime::from_str(timestamp).unwrap();
+
+        // Bytes written in place can't be told from the file, only the manifest knows them
+        let in_place_sizes = match ResumeManifest::load(&self.config.cache_dir, iri).await? {
+            Some(manifest) if manifest.single_file => Some(manifest.segments.iter().map(|segment| segment.downloaded).collect()),
+            _ => None,
+        };
+        self.config.single_file = in_place_sizes.is_some();
+
+        let cache_sizes = match in_place_sizes {
+            Some(sizes) => sizes,
+            None => get_cache_sizes(&http_task, threads, self.config.clone())?,
+        };
+
+        http_task.calculate_x_offsets(&cache_sizes);
+
+        http_task
+            .total_downloaded
+            .fetch_add(cache_sizes.iter().sum::<u64>(), Ordering::SeqCst);
+
+        Ok(http_task)
+    }
+
+    // Picks up the download of `iri` left behind by Ctrl-C, along with its history id
+    async fn resume_interrupted(&mut self, iri: &IriString) -> Result<Option<(HttpTask, String)>, RawstErr> {
+        let Some(manifest) = ResumeManifest::load(&self.config.cache_dir, iri).await? else {
+            return Ok(None);
+        };
+
+        log::info!("Resuming interrupted download of {}", iri);
+        let http_task = self
+            .resume_task(iri, &manifest.filename, manifest.download_dir.clone(), manifest.threads, &manifest.timestamp, &manifest.headers)
+            .await?;
+
+        if !manifest.matches(&http_task) {
+            status(format!("Warning!: {} changed since it was interrupted, downloading it again", iri));
+            ResumeManifest::remove(&self.config.cache_dir, iri).await?;
+
+            return Ok(None);
+        }
+
+        Ok(Some((http_task, manifest.id)))
+    }
+
+    pub async fn process_resume_request(&mut self, id: String) -> Result<Option<DownloadSummary>, RawstErr> {
+        log::trace!("Resuming download (id:{:?}, config:{:?})", id, self.config);
+        let started = Instant::now();
+        let record = if id == "auto" {
+            self.history_manager.get_recent_pending()?
+        } else {
+            self.history_manager.get_record(&id)?
+        };
+
+        let Some(data) = record else {
+            status(format!("Record with id {:?} not found", id));
+
+            return Ok(None);
+        };
+
+        if !data.is_resumable() {
+            status("The file is already downloaded");
+
+            return Ok(Some(DownloadSummary::of_record(&data, started.elapsed())));
+        }
+
+        let mut http_task = self
+            .resume_task(&data.iri, &data.file_name, data.file_location.clone(), data.threads_used, &data.timestamp, &data.headers)
+            .await?;
+        http_task.checksum = stored_checksum(&data);
+
+        let output_path = self.config.download_dir.join(&http_task.filename);
+        // Shares the progress counters, so it knows how far the download got
+        let progress = http_task.clone();
+        let resumed_at = progress.total_downloaded.load(Ordering::SeqCst);
+        let result = self.http_download(http_task).await;
+        if result.is_ok() {
+            ResumeManifest::remove(&self.config.cache_dir, &data.iri).await?;
+        }
+
+        let final_checksum = saved_checksum(&result, &progress, &output_path).await;
+        self.history_manager.update_record(data.id, result.is_ok(), final_checksum.clone())?;
+        result?;
+
+        let summary = self.summary_of(&progress, output_path, resumed_at, started.elapsed(), final_checksum);
+        if self.summaries {
+            emitter().emit(Event::Downloaded(summary.clone()));
+        }
+
+        Ok(Some(summary))
+    }
+
+    pub async fn http_download(&self, task: HttpTask) -> Result<(), RawstErr> {
+        log::trace!("Starting HTTP download (task:{task:?})");
+        // eg. the subfolder of `subdir_by`
+        tokio::fs::create_dir_all(&self.config.download_dir).await.map_err(RawstErr::FileError)?;
+
+        // Chunks are written to the segment dir first when downloading concurrently, the merged file always ends up in download_dir
+        let remaining = task.content_length().saturating_sub(task.total_downloaded.load(Ordering::SeqCst));
+        if self.config.threads > 1 && !writes_in_place(&task, &self.config) {
+            ensure_space(self.config.segment_dir(), remaining)?;
+            ensure_space(&self.config.download_dir, task.content_length())?;
+        } else {
+            ensure_space(&self.config.download_dir, remaining)?;
+        }
+
+        let progress = self.progress.add(
+            task.iri.as_str(),
+            &task.filename.display().to_string(),
+            task.known_length(),
+            task.total_downloaded.load(Ordering::SeqCst),
+        );
+
+        // Segments and merges of the download are reported within it
+        let span = tracing::info_span!("download", url = %task.iri, file = %task.filename.display(), bytes = tracing::field::Empty);
+        let scheme = Scheme::of(&task.iri)?;
+        let result = async {
+            match scheme {
+                // There's nothing to split or to request, the bar completes right away
+                _ if task.known_length() == Some(0) => create_empty_file(&task, &self.config.download_dir).await,
+                Scheme::Http => self.http_handler.download(&task, progress.as_ref(), &self.config).await,
+                Scheme::Ftp => self.ftp.download(&task, progress.as_ref(), &self.config).await,
+                Scheme::File => self.file.download(&task, progress.as_ref(), &self.config).await,
+            }
+        }
+        .instrument(span.clone())
+        .await;
+        span.record("bytes", task.total_downloaded.load(Ordering::SeqCst));
+
+        match &result {
+            Ok(()) => progress.finish(),
+            Err(err) => progress.fail(err),
+        }
+
+        result
+    }
+
+    /// Creates a task from the first URL that responds, keeping the others that agree with it as mirrors
+    pub async fn download_with_mirrors(
+        &mut self,
+        urls: Vec<IriString>,
+        save_as: Option<&PathBuf>,
+        additional_headers: &HashMap<String, String>,
+        index: usize
+    ) -> Result<HttpTask, RawstErr> {
+        let mut urls = urls.into_iter();
+        let mut last_err = RawstErr::InvalidArgs;
+
+        let mut task = loop {
+            let Some(iri) = urls.next() else {
+                return Err(last_err);
+            };
+
+            match self.create_http_task(iri.clone(), save_as, additional_headers, index).await {
+                Ok(task) => break task,
+                Err(err) => {
+                    log::warn!("Skipping {iri}: {err}");
+                    last_err = err;
+                }
+            }
+        };
+
+        for mirror in urls {
+            // Downloads go through the backend of the main URL
+            if Scheme::of(&mirror).ok() != Scheme::of(&task.iri).ok() {
+                log::warn!("Skipping mirror {mirror}: it uses another protocol than {}", task.iri);
+                continue;
+            }
+
+            let meta = match self.fetch_meta(&mirror, additional_headers).await {
+                Ok((headers, _)) => ResourceMeta::from_headers(&headers),
+                Err(err) => {
+                    log::warn!("Skipping mirror {mirror}: {err}");
+                    continue;
+                }
+            };
+
+            // Without both lengths there's no telling whether the mirror has the same file
+            match (task.known_length(), meta.length) {
+                (Some(expected), Some(actual)) if expected != actual => {
+                    return Err(RawstErr::MirrorMismatch { mirror: mirror.to_string(), expected, actual });
+                }
+                (Some(_), Some(_)) => {}
+                _ => {
+                    log::warn!("Skipping mirror {mirror}: its length can't be compared with {}", task.iri);
+                    continue;
+                }
+            }
+
+            // Segments can't be fetched from a mirror that ignores ranges
+            if self.config.threads > 1 && !meta.supports_ranges {
+                log::warn!("Skipping mirror {mirror}: it doesn't support ranges");
+                continue;
+            }
+
+            task.mirrors.push(mirror);
+        }
+
+        Ok(task)
+    }
+
+    // Asks the backend of the URL's scheme about it
+    async fn fetch_meta(&self, iri: &IriString, additional_headers: &HashMap<String, String>) -> Result<(HeaderMap, IriString), RawstErr> {
+        match Scheme::of(iri)? {
+            Scheme::Http => self.http_handler.fetch_meta(iri, additional_headers).await,
+            Scheme::Ftp => self.ftp.fetch_meta(iri, additional_headers).await,
+            Scheme::File => self.file.fetch_meta(iri, additional_headers).await,
+        }
+    }
+
+    /// Applies the overwrite policy when the output file of a new download already exists
+    ///
+    /// Not used when resuming, the partial file is the download's own.
+    fn resolve_collision(&self, task: &mut HttpTask, overwrite_policy: OverwritePolicy) -> Result<(), RawstErr> {
+        let output_path = self.config.download_dir.join(&task.filename);
+        if !output_path.exists() {
+            return Ok(());
+        }
+
+        match overwrite_policy {
+            OverwritePolicy::Error => Err(RawstErr::OutputExists(output_path)),
+            OverwritePolicy::Overwrite => {
+                log::info!("Overwriting {:?}", output_path);
+                Ok(())
+            }
+            OverwritePolicy::Rename => {
+                let unique = unique_path(&self.config.download_dir, &task.filename);
+                log::info!("{:?} exists, saving as {:?}", output_path, unique);
+                task.filename = PathBuf::from(unique.file_name().unwrap());
+                Ok(())
+            }
+        }
+    }
+
+    // Reserves the output of `task` among the downloads sharing this engine
+    //
+    // Files are only created once complete, so a batch resolving two URLs to the
+    // same name wouldn't see the other one's file in `resolve_collision`.
+    fn claim_output(&self, task: &mut HttpTask, overwrite_policy: OverwritePolicy) -> Result<OutputClaim, RawstErr> {
+        let mut claimed = self.claimed_outputs.lock().unwrap();
+        let mut output_path = self.config.download_dir.join(&task.filename);
+
+        if let Some(other) = claimed.get(&output_path).and_then(|urls| urls.first()) {
+            log::warn!("{} and {} both resolve to {:?}", other, task.iri, output_path);
+            status(format!("Warning!: {} and {} both save to {}", other, task.iri, output_path.display()));
+
+            match overwrite_policy {
+                OverwritePolicy::Error => return Err(RawstErr::OutputExists(output_path)),
+                // The one finishing last is kept
+                OverwritePolicy::Overwrite => {}
+                OverwritePolicy::Rename => {
+                    output_path = unique_path_by(&self.config.download_dir, &task.filename, |path| {
+                        !path.exists() && !claimed.contains_key(path)
+                    });
+                    log::info!("Saving {} as {:?} instead", task.iri, output_path);
+                    task.filename = PathBuf::from(output_path.file_name().unwrap());
+                }
+            }
+        }
+
+        claimed.entry(output_path.clone()).or_default().push(task.iri.to_string());
+
+        Ok(OutputClaim {
+            claimed: self.claimed_outputs.clone(),
+            path: output_path,
+        })
+    }
+
+    pub async fn create_http_task(
+        &mut self,
+        iri: IriString,
+        save_as: Option<&PathBuf>,
+        additional_headers: &HashMap<String, String>,
+        index: usize
+    ) -> Result<HttpTask, RawstErr> {
+        log::trace!("Creating HTTP download task (iri:{iri:?}, save_as:{save_as:?})");
+        let scheme = Scheme::of(&iri)?;
+        let (cached_headers, final_iri) = self.fetch_meta(&iri, additional_headers).await?;
+        let meta = ResourceMeta::from_headers(&cached_headers);
+        log::debug!("Resource metadata: {meta:?} (resolved to {final_iri})");
+
+        if self.config.sanitize_paths == SanitizePolicy::Reject {
+            let name = server_filename(&cached_headers, &final_iri);
+            if is_unsafe_filename(&name) {
+                return Err(RawstErr::UnsafeFileName(name));
+            }
+        }
+
+        if let (Some(limit), Some(length)) = (self.config.max_size, meta.length) {
+            if length > limit {
+                return Err(RawstErr::SizeLimitExceeded { limit });
+            }
+        }
+
+        if let (Some(expected), Some(actual)) = (self.expected_size, meta.length) {
+            if actual != expected {
+                return Err(RawstErr::SizeMismatch { expected, actual });
+            }
+        }
+
+        let mut filename = match &meta.filename_hint {
+            Some(hint) => PathBuf::from(hint),
+            None => extract_filename_from_url(&final_iri),
+        };
+
+        // A directory only says where the file goes, it keeps the name it would have had
+        let save_in = save_as.filter(|save_as| is_directory_output(save_as));
+        if let Some(dir) = save_in {
+            self.config.download_dir = dir.to_path_buf();
+
+        }
+
+        if let Some(save_as) = save_as.filter(|_| save_in.is_none()) {
+            let output_path = save_as.parent().unwrap();
+            if output_path.exists() {
+                self.config.download_dir = output_path.to_path_buf();
+
+            }
+
+            filename = PathBuf::from(save_as.file_name().unwrap());
+            assert!(filename.is_relative());
+
+        } else if let Some(template) = &self.config.filename_template {
+            filename = render_filename_template(template, &filename, &final_iri, index)?;
+
+        }
+
+        // Whatever the name came from, the download must stay in `download_dir`
+        if !is_plain_filename(&filename) {
+            return Err(RawstErr::UnsafeFileName(filename.display().to_string()));
+        }
+
+        // Created along with the file, a dry run leaves it alone
+        if save_as.is_none() {
+            if let Some(subdir) = self.config.subdir_by.subdir(&iri) {
+                self.config.download_dir.push(subdir);
+            }
+        }
+
+        let mut task = HttpTask::new(iri, filename, cached_headers, additional_headers.to_owned());
+        task.max_size = self.config.max_size;
+        task.expected_size = self.expected_size;
+
+        // Segments are found while downloading, the task only has the single stream to fall back to
+        let dynamic_split = self.config.dynamic_split && self.config.threads > 1 && meta.length.is_none() && meta.supports_ranges && scheme == Scheme::Http;
+
+        // checks if the server allows to receive byte ranges for concurrent download
+        // otherwise uses single thread
+        if dynamic_split {
+            log::info!("{final_iri} has no Content-Length, splitting it while it downloads");
+            task.calculate_chunks(1);
+
+            return Ok(task);
+
+        } else if self.config.threads > 1 && meta.length.is_none() {
+            // Segments are cut from the total size, a chunked response has none
+            log::info!("{final_iri} has no Content-Length, downloading it in a single stream instead of {} segments", self.config.threads);
+
+        } else if self.config.threads > 1 && !meta.supports_ranges && scheme == Scheme::Http {
+            status("Warning!: Server doesn't allow partial content, sequentially downloading..");
+
+        }
+
+        let threads = effective_threads(meta.length, self.config.threads, meta.supports_ranges);
+        if threads != self.config.threads {
+            log::debug!("Using {threads} threads instead of {} for {:?} bytes", self.config.threads, meta.length);
+            self.config.threads = threads;
+        }
+
+        task.calculate_chunks(self.config.threads as u64);
+
+        Ok(task)
+    }
+}
+
+// Checksum to record for a download of `task` to `path`, the expected one or else what was saved
+async fn saved_checksum(result: &Result<(), RawstErr>, task: &HttpTask, path: &Path) -> Option<String> {
+    match (result, &task.checksum) {
+        (Ok(()), Some(checksum)) => Some(checksum.to_string()),
+        (Ok(()), None) => file_checksum(path).await.ok().map(|checksum| checksum.to_string()),
+        (Err(_), _) => None,
+    }
+}
+
+// Reports how a batch went, failing if any of its downloads did
+fn batch_outcome(results: Vec<(String, Result<DownloadSummary, RawstErr>)>) -> Result<Vec<DownloadSummary>, RawstErr> {
+    let failed: Vec<BatchFailure> = results
+        .iter()
+        .filter_map(|(url, result)| result.as_ref().err().map(|err| BatchFailure { url: url.clone(), error: err.to_string() }))
+        .collect();
+    let failed_count = failed.len();
+    emitter().emit(Event::BatchFinished { succeeded: results.len() - failed_count, failed });
+
+    if failed_count == 0 {
+        Ok(results.into_iter().filter_map(|(_, result)| result.ok()).collect())
+    } else {
+        Err(RawstErr::BatchFailed { failed: failed_count, total: results.len() })
+    }
+}
+
+// Checksum the download of `record` was started with, if it's readable
+fn stored_checksum(record: &Record) -> Option<Checksum> {
+    match record.checksum.as_deref().map(parse_checksum)? {
+        Ok(checksum) => Some(checksum),
+        Err(err) => {
+            log::warn!("Ignoring the checksum recorded for {}: {}", record.id, err);
+            None
+        }
+    }
+}
+
+// Whether the file of a completed download is still there with the same size and checksum
+async fn is_intact(record: &Record) -> bool {
+    let path = record.file_path();
+
+    match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.len() == record.file_size => {}
+        _ => return false,
     }
-}
\ No newline at end of file
+
+    match record.checksum.as_deref().map(parse_checksum) {
+        Some(Ok(checksum)) => verify_checksum(&path, &checksum).await.is_ok(),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::task::ChunkType;
+    use crate::core::testing::{config_in, MockResponse, MockServer, TempDir};
+
+    fn engine(config: Config) -> Engine {
+        Engine::new(config).unwrap().with_progress_reporter(Arc::new(NoProgress))
+    }
+
+    #[tokio::test]
+    async fn downloads_without_content_length_use_a_single_stream() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.threads = 8;
+        let body = "streamed line\n".repeat(10_000);
+        let server = MockServer::start({
+            let body = body.clone();
+            move |_| MockResponse::new(200, body.as_str()).header("Accept-Ranges", "bytes").without_length()
+        })
+        .await;
+        let mut engine = engine(config);
+
+        let task = engine.create_http_task(server.url("/stream.log"), None, &HashMap::new(), 0).await.unwrap();
+
+        assert_eq!(task.known_length(), None);
+        assert_eq!(engine.config.threads, 1);
+        assert!(matches!(task.chunk_data, ChunkType::Single(_)));
+
+        engine.http_download(task).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(engine.config.download_dir.join("stream.log")).unwrap(), body);
+        let gets: Vec<_> = server.requests().into_iter().filter(|request| request.method == "GET").collect();
+        assert_eq!(gets.len(), 1);
+        assert_eq!(gets[0].header("range"), None);
+    }
+
+    // Creates the task of `/file.bin` from a server of `body`, with a mirror answering `mirror`
+    async fn task_with_mirror(body: &'static str, mirror: fn() -> MockResponse) -> Result<HttpTask, RawstErr> {
+        let dir = TempDir::new();
+        let main = MockServer::start(move |_| MockResponse::new(200, body).header("Accept-Ranges", "bytes")).await;
+        let mirror = MockServer::start(move |_| mirror()).await;
+        let mut engine = engine(config_in(dir.path()));
+
+        engine.download_with_mirrors(vec![main.url("/file.bin"), mirror.url("/file.bin")], None, &HashMap::new(), 0).await
+    }
+
+    #[tokio::test]
+    async fn mirrors_of_the_same_length_are_kept() {
+        let task = task_with_mirror("contents", || MockResponse::new(200, "contents").header("Accept-Ranges", "bytes")).await.unwrap();
+
+        assert_eq!(task.mirrors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn mirrors_of_unknown_length_are_skipped() {
+        let task = task_with_mirror("contents", || MockResponse::new(200, "contents").header("Accept-Ranges", "bytes").without_length()).await.unwrap();
+
+        assert!(task.mirrors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn mirrors_of_another_length_are_a_mismatch() {
+        let result = task_with_mirror("contents", || MockResponse::new(200, "other contents").header("Accept-Ranges", "bytes")).await;
+
+        assert!(matches!(result, Err(RawstErr::MirrorMismatch { expected: 8, actual: 14, .. })), "{:?}", result);
+    }
+
+    // `rawst download` with `args`
+    fn download_args(args: &[&str]) -> DownloadArgs {
+        use clap::Parser;
+
+        let arguments = crate::cli::args::Arguments::try_parse_from(["rawst", "download"].iter().chain(args)).unwrap();
+        match arguments.command {
+            Some(crate::cli::args::Command::Download(args)) => args,
+            other => panic!("expected a download, got {:?}", other),
+        }
+    }
+
+    // Downloads `/served.bin` with `-o output`, returning where the summary says it went
+    async fn download_to(dir: &TempDir, body: &'static str, output: &Path) -> PathBuf {
+        let server = MockServer::start(move |_| MockResponse::new(200, body)).await;
+        let url = server.url("/served.bin").to_string();
+        let args = download_args(&[&url, "-o", output.to_str().unwrap()]);
+
+        let summaries = download(args, config_in(dir.path())).await.unwrap();
+
+        assert_eq!(summaries.len(), 1);
+        summaries[0].path.clone()
+    }
+
+    #[tokio::test]
+    async fn output_to_an_existing_dir_keeps_the_server_name() {
+        let dir = TempDir::new();
+        let output = dir.path().join("elsewhere");
+        std::fs::create_dir(&output).unwrap();
+
+        let path = download_to(&dir, "contents", &output).await;
+
+        assert_eq!(path, output.join("served.bin"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "contents");
+    }
+
+    #[tokio::test]
+    async fn output_to_a_new_dir_with_a_separator_creates_it() {
+        let dir = TempDir::new();
+        let output = dir.path().join("new").join("");
+        assert!(!output.exists());
+
+        let path = download_to(&dir, "contents", &output).await;
+
+        assert_eq!(path, dir.path().join("new").join("served.bin"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "contents");
+    }
+
+    #[tokio::test]
+    async fn output_to_a_file_path_renames_the_download() {
+        let dir = TempDir::new();
+        let output = dir.path().join("renamed.bin");
+
+        let path = download_to(&dir, "contents", &output).await;
+
+        assert_eq!(path, output);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "contents");
+        assert!(!dir.path().join("served.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn output_to_dash_writes_nothing_to_disk() {
+        let dir = TempDir::new();
+
+        // Empty, the test output is where stdout goes
+        let path = download_to(&dir, "", Path::new("-")).await;
+
+        assert_eq!(path, PathBuf::from("-"));
+        assert!(!dir.path().join("-").exists());
+        assert_eq!(std::fs::read_dir(dir.path().join("downloads")).unwrap().count(), 0);
+    }
+
+    // Downloads a metalink of one `/served.bin` of `size` bytes from the server answering with `response`
+    async fn download_metalink(dir: &TempDir, size: u64, response: fn() -> MockResponse) -> Result<Vec<DownloadSummary>, RawstErr> {
+        let server = MockServer::start(move |_| response()).await;
+        let metalink = dir.path().join("served.meta4");
+        std::fs::write(&metalink, format!(
+            r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink"><file name="served.bin"><size>{}</size><url>{}</url></file></metalink>"#,
+            size,
+            server.url("/served.bin"),
+        )).unwrap();
+
+        engine(config_in(dir.path())).process_metalink_download(metalink, HashMap::new()).await
+    }
+
+    #[tokio::test]
+    async fn metalink_downloads_of_the_given_size_succeed() {
+        let dir = TempDir::new();
+
+        let summaries = download_metalink(&dir, 8, || MockResponse::new(200, "contents")).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&summaries[0].path).unwrap(), "contents");
+    }
+
+    #[tokio::test]
+    async fn metalink_sizes_are_checked_against_the_content_length() {
+        let dir = TempDir::new();
+
+        let result = download_metalink(&dir, 100, || MockResponse::new(200, "contents")).await;
+
+        assert!(matches!(result, Err(RawstErr::SizeMismatch { expected: 100, actual: 8 })), "{:?}", result);
+        assert!(!dir.path().join("downloads").join("served.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn metalink_sizes_remove_streams_of_another_size() {
+        let dir = TempDir::new();
+
+        let result = download_metalink(&dir, 100, || MockResponse::new(200, "contents").without_length()).await;
+
+        assert!(matches!(result, Err(RawstErr::SizeMismatch { expected: 100, actual: 8 })), "{:?}", result);
+        let downloads = dir.path().join("downloads");
+        let left: Vec<_> = std::fs::read_dir(&downloads).map(|entries| entries.collect()).unwrap_or_default();
+        assert!(left.is_empty(), "left behind {:?}", left);
+    }
+
+    #[tokio::test]
+    async fn metalinks_name_the_files_they_describe() {
+        let dir = TempDir::new();
+        let server = MockServer::start(|_| MockResponse::new(200, "contents")).await;
+        let metalink = dir.path().join("served.meta4");
+        std::fs::write(&metalink, format!(
+            r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink"><file name="../isos/renamed.bin"><url>{}</url></file></metalink>"#,
+            server.url("/served.bin"),
+        )).unwrap();
+
+        let summaries = engine(config_in(dir.path())).process_metalink_download(metalink, HashMap::new()).await.unwrap();
+
+        // The directories are left out, it's saved in the download directory
+        assert_eq!(summaries[0].path, dir.path().join("downloads").join("renamed.bin"));
+        assert_eq!(std::fs::read_to_string(&summaries[0].path).unwrap(), "contents");
+    }
+
+    fn stats_task() -> HttpTask {
+        HttpTask::new(IriString::try_from("http://example.com/file.iso").unwrap(), PathBuf::from("file.iso"), HeaderMap::new(), HashMap::new())
+    }
+
+    #[test]
+    fn stats_of_a_single_stream_are_one_segment() {
+        let task = stats_task();
+        task.total_downloaded.store(3000, Ordering::SeqCst);
+
+        let stats = DownloadStats::of(&task, 1000, Duration::from_secs(1), Duration::from_secs(2));
+
+        assert_eq!(stats.segments, vec![SegmentReport {
+            index: 0,
+            range: "1000-2999".to_string(),
+            bytes: 2000,
+            elapsed: Duration::from_secs(1),
+            avg_bps: 2000.0,
+        }]);
+        assert_eq!(stats.bytes, 2000);
+        assert_eq!(stats.avg_bps, 1000.0);
+    }
+
+    #[test]
+    fn stats_are_a_json_event() {
+        let task = stats_task();
+        for (index, start) in [(1, 500), (0, 0)] {
+            task.record_segment(SegmentStats {
+                index,
+                range: ByteRange { start, end: Some(start + 499) },
+                bytes: 500,
+                elapsed: Duration::from_millis(250),
+            });
+        }
+
+        let event = Event::Stats(DownloadStats::of(&task, 0, Duration::ZERO, Duration::from_secs(1)));
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(json["event"], "stats");
+        assert_eq!(json["url"], "http://example.com/file.iso");
+        assert_eq!(json["bytes"], 1000);
+        assert_eq!(json["seconds"], 1.0);
+        assert_eq!(json["segments"][0]["range"], "0-499");
+        assert_eq!(json["segments"][1]["range"], "500-999");
+        assert_eq!(json["segments"][1]["seconds"], 0.25);
+        assert_eq!(json["segments"][1]["avg_bps"], 2000.0);
+    }
+
+    #[test]
+    fn plans_are_a_json_event() {
+        let event = Event::Plan(DownloadPlan {
+            url: "http://example.com/file.iso".to_string(),
+            mirrors: vec!["http://mirror.example.org/file.iso".to_string()],
+            file: PathBuf::from("file.iso"),
+            bytes: None,
+            supports_ranges: true,
+            threads: 4,
+            destination: PathBuf::from("/srv/downloads/file.iso"),
+        });
+
+        assert_eq!(serde_json::to_value(&event).unwrap(), serde_json::json!({
+            "event": "plan",
+            "url": "http://example.com/file.iso",
+            "mirrors": ["http://mirror.example.org/file.iso"],
+            "file": "file.iso",
+            "bytes": null,
+            "supports_ranges": true,
+            "threads": 4,
+            "destination": "/srv/downloads/file.iso",
+        }));
+    }
+
+    // A completed download of `contents` in history, with the checksum it had
+    fn record_download(config: &Config, file_name: &str, contents: &str, checksum: &str) -> PathBuf {
+        let path = config.download_dir.join(file_name);
+        std::fs::write(&path, contents).unwrap();
+
+        let mut record = Record::new(
+            "1".to_string(),
+            IriString::try_from(format!("http://example.com/{}", file_name)).unwrap(),
+            PathBuf::from(file_name),
+            contents.len() as u64,
+            config.download_dir.clone(),
+            1,
+            chrono::Local::now().to_string(),
+            HashMap::new(),
+            Some(checksum.to_string()),
+        );
+        record.status = "Completed".to_string();
+        HistoryManager::new(config.history_file_path.clone()).append(record).unwrap();
+
+        path
+    }
+
+    #[tokio::test]
+    async fn verify_accepts_an_intact_file() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        // sha256 of "hello"
+        let path = record_download(&config, "hello.txt", "hello", "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+
+        verify(VerifyArgs { file: path }, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_rejects_a_changed_file() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        let path = record_download(&config, "hello.txt", "hello", "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        std::fs::write(&path, "jello").unwrap();
+
+        assert!(verify(VerifyArgs { file: path.clone() }, config).await.is_err());
+        // Only checked, never removed
+        assert!(path.exists());
+    }
+
+    // A download of `url` that history has as interrupted before anything was saved
+    fn pending_record(config: &Config, url: IriString, checksum: Option<&str>) -> String {
+        let record = Record::new(
+            "pending".to_string(),
+            url,
+            PathBuf::from("resumed.bin"),
+            8,
+            config.download_dir.clone(),
+            1,
+            chrono::Local::now().to_string(),
+            HashMap::new(),
+            checksum.map(str::to_string),
+        );
+        HistoryManager::new(config.history_file_path.clone()).append(record).unwrap();
+
+        "pending".to_string()
+    }
+
+    #[tokio::test]
+    async fn resumed_downloads_return_their_summary() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        let server = MockServer::start(|_| MockResponse::new(200, "contents")).await;
+        // sha256 of "contents"
+        let checksum = "sha256:d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8";
+        let id = pending_record(&config, server.url("/resumed.bin"), Some(checksum));
+
+        let summaries = resume_download(ResumeArgs { download_ids: vec![id.clone()] }, config.clone()).await.unwrap();
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].path, config.download_dir.join("resumed.bin"));
+        assert_eq!(summaries[0].bytes, 8);
+        assert_eq!(summaries[0].checksum.as_deref(), Some(checksum));
+        assert_eq!(std::fs::read_to_string(&summaries[0].path).unwrap(), "contents");
+        let record = HistoryManager::new(config.history_file_path.clone()).get_record(&id).unwrap().unwrap();
+        assert_eq!(record.status, "Completed");
+    }
+
+    #[tokio::test]
+    async fn resumed_downloads_are_checked_against_the_recorded_checksum() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        let server = MockServer::start(|_| MockResponse::new(200, "contents")).await;
+        let id = pending_record(&config, server.url("/resumed.bin"), Some("sha256:0000000000000000000000000000000000000000000000000000000000000000"));
+
+        let result = resume_download(ResumeArgs { download_ids: vec![id] }, config.clone()).await;
+
+        assert!(matches!(result, Err(RawstErr::ChecksumMismatch { .. })), "got {:?}", result);
+        assert!(!config.download_dir.join("resumed.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn completed_and_unknown_downloads_are_not_resumed() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        record_download(&config, "hello.txt", "hello", "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+
+        let summaries = resume_download(ResumeArgs { download_ids: vec!["1".to_string(), "unknown".to_string()] }, config.clone()).await.unwrap();
+
+        // Only the completed one has a summary, without any segment downloaded
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].path, config.download_dir.join("hello.txt"));
+        assert_eq!(summaries[0].segments, 0);
+    }
+
+    async fn interrupt(config: &Config, url: IriString, id: &str) {
+        let manifest = ResumeManifest {
+            url,
+            id: id.to_string(),
+            filename: PathBuf::from("resumed.bin"),
+            download_dir: config.download_dir.clone(),
+            threads: 1,
+            timestamp: chrono::Local::now().to_string(),
+            headers: HashMap::new(),
+            segments: Vec::new(),
+            single_file: false,
+        };
+        manifest.save(&config.cache_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn continuing_all_downloads_goes_on_after_a_failure() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/missing.bin" => MockResponse::new(404, "not found"),
+            _ => MockResponse::new(200, "contents"),
+        })
+        .await;
+        interrupt(&config, server.url("/missing.bin"), "missing").await;
+        interrupt(&config, server.url("/resumed.bin"), "resumed").await;
+
+        let result = continue_downloads(true, config.clone()).await;
+
+        assert!(matches!(result, Err(RawstErr::BatchFailed { failed: 1, total: 2 })), "got {:?}", result);
+        assert_eq!(std::fs::read_to_string(config.download_dir.join("resumed.bin")).unwrap(), "contents");
+    }
+
+    #[tokio::test]
+    async fn continued_downloads_are_checked_against_the_recorded_checksum() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        let server = MockServer::start(|_| MockResponse::new(200, "contents")).await;
+        let id = pending_record(&config, server.url("/resumed.bin"), Some("sha256:0000000000000000000000000000000000000000000000000000000000000000"));
+        interrupt(&config, server.url("/resumed.bin"), &id).await;
+
+        let result = continue_downloads(false, config.clone()).await;
+
+        assert!(matches!(result, Err(RawstErr::ChecksumMismatch { .. })), "got {:?}", result);
+    }
+}
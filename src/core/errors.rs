@@ -1,45 +1,142 @@
-use std::fmt;
 use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use reqwest::Error as ReqwestError;
+use reqwest::StatusCode;
+use suppaftp::FtpError;
+use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum RawstErr {
     // Startup
+    #[error("Initialisation failed.")]
     InitilisationError,
+    #[error("Invalid Arguments or No Arguments")]
     InvalidArgs,
+    #[error("Config Error: {0}")]
+    ConfigParse(String),
+    #[error("Invalid Header: {0}")]
+    InvalidHeader(String),
+    #[error("Invalid Filename Template: {0}")]
+    InvalidTemplate(String),
+    #[error("Metalink Error: {0}")]
+    MetalinkParse(String),
+    #[error("Unsupported Scheme: {0}:// URLs can't be downloaded, use http://, https://, ftp:// or file://")]
+    UnsupportedScheme(String),
     // Download
-    HttpError(ReqwestError),
-    Unknown(ReqwestError),
-    BadRequest,
-    Unauthorized,
-    Forbidden,
-    NotFound,
-    InternalServerError,
+    #[error("HTTP Error: {0}")]
+    HttpError(#[source] ReqwestError),
+    #[error("Timeout: The server took too long to connect or to send data")]
+    Timeout,
+    #[error("Too Many Redirects: The request was redirected more times than allowed by max_redirects")]
+    TooManyRedirects,
+    #[error("Mirror Mismatch: {mirror} reports {actual} bytes but the file has {expected} bytes")]
+    MirrorMismatch { mirror: String, expected: u64, actual: u64 },
+    #[error("Batch Failed: {failed} of {total} downloads failed")]
+    BatchFailed { failed: usize, total: usize },
+    #[error("Interrupted: The download was stopped with Ctrl-C")]
+    Interrupted,
+    #[error("HTTP {code} {}: {url} responded with an error{}", status_reason(*.code), status_description(*.code))]
+    HttpStatus { code: u16, url: String, retry_after: Option<Duration> },
+    #[error("Range Ignored: {0} sent the whole file instead of the requested bytes")]
+    RangeIgnored(String),
+    #[error("Resource Changed: {0} changed while it was being downloaded, its segments can't be put together")]
+    ResourceChanged(String),
+    #[error("Unsafe File Name: the server named the file {0:?}, which could point outside the download directory")]
+    UnsafeFileName(String),
+    #[error("Range Out Of Bounds: bytes {range} are past the end of the {length} byte file")]
+    RangeOutOfBounds { range: String, length: u64 },
+    #[error("Unreachable: The request was not able to reach the server")]
     Unreachable,
+    #[error("FTP Error: {0}")]
+    FtpError(#[source] FtpError),
     // Save
-    FileError(io::Error),
+    #[error("File Error: {0}")]
+    FileError(#[source] io::Error),
+    #[error("Output Exists: {} is already there, use --overwrite to replace it or --no-clobber to save under a new name", .0.display())]
+    OutputExists(PathBuf),
+    #[error("History Error: {0}")]
+    HistoryParse(String),
+    #[error("Not In History: no completed download was saved as {}", .0.display())]
+    NotInHistory(PathBuf),
+    #[error("No Checksum: history has no checksum for {}, it can't be verified", .0.display())]
+    NoChecksum(PathBuf),
+    #[error("Confirmation Needed: {} is {bytes} bytes, above confirm_above_bytes, and there's no terminal to ask on, pass --yes to download it", .file.display())]
+    ConfirmationNeeded { file: PathBuf, bytes: u64 },
+    #[error("Declined: {} wasn't downloaded", .0.display())]
+    Declined(PathBuf),
+    #[error("Insufficient Space: the download needs {needed} bytes but only {available} bytes are available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error("Checksum Conflict: {first} doesn't match {second}")]
+    ChecksumConflict { first: String, second: String },
+    #[error("Checksum Mismatch: expected {expected} but the downloaded file hashes to {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("Size Limit Exceeded: the download is larger than the {limit} bytes allowed by max_size")]
+    SizeLimitExceeded { limit: u64 },
+    #[error("Size Mismatch: expected {expected} bytes but the downloaded file has {actual} bytes")]
+    SizeMismatch { expected: u64, actual: u64 },
 }
 
-impl fmt::Display for RawstErr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+/// Exit code of `rawst verify` when there's nothing to verify the file against
+pub const NO_CHECKSUM_EXIT_CODE: u8 = 3;
+
+impl RawstErr {
+    /// Status rawst exits with when it fails with this error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            RawstErr::NoChecksum(_) => NO_CHECKSUM_EXIT_CODE,
+            _ => 1,
+        }
+    }
+
+    /// Wraps a reqwest error, singling out timeouts and redirect loops
+    pub fn from_http(err: ReqwestError) -> RawstErr {
+        if err.is_timeout() {
+            RawstErr::Timeout
+        } else if err.is_redirect() {
+            RawstErr::TooManyRedirects
+        } else {
+            RawstErr::HttpError(err)
+        }
+    }
+
+    /// Whether retrying the same request might succeed
+    ///
+    /// Connection problems, timeouts and server errors are transient,
+    /// client errors (4xx) are not.
+    pub fn is_transient(&self) -> bool {
         match self {
-            // Startup
-            RawstErr::InitilisationError => write!(f, "Initialisation failed."),
-            RawstErr::InvalidArgs => write!(f, "Invalid Arguments or No Arguments"),
-            // Download
-            RawstErr::HttpError(err) => write!(f, "HTTP Error: {}", err),
-            RawstErr::BadRequest => write!(f, "Bad Request: The server cannot or will not process the request due to something that is perceived to be a client error."),
-            RawstErr::Unauthorized => write!(f, "Unauthorized: The request has not been applied because it lacks valid authentication credentials for the target resource."),
-            RawstErr::Forbidden => write!(f, "Forbidden: The server understood the request, but it refuses to authorize it."),
-            RawstErr::NotFound => write!(f, "Not Found: The server has not found anything matching the Request-URI."),
-            RawstErr::InternalServerError => write!(f, "Internal Server Error: The server encountered an unexpected condition which prevented it from fulfilling the request."),
-            RawstErr::Unreachable => write!(f, "Unreachable: The request was not able to reach the server"),
-            RawstErr::Unknown(err) => write!(f, "Unknow Error: {}", err),
-            // Save
-            RawstErr::FileError(err) => write!(f, "File Error: {}", err),
+            RawstErr::HttpError(err) => err.is_timeout() || err.is_connect() || err.is_body() || err.is_request(),
+            // Server errors, plus timeouts and rate limiting reported by the server
+            RawstErr::HttpStatus { code, .. } => *code >= 500 || *code == 408 || *code == 429,
+            RawstErr::FtpError(err) => matches!(err, FtpError::ConnectionError(_)),
+            RawstErr::Timeout | RawstErr::Unreachable => true,
+            _ => false,
+        }
+    }
+
+    /// How long the server asked to wait before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            RawstErr::HttpStatus { retry_after, .. } => *retry_after,
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for RawstErr {}
+fn status_reason(code: u16) -> &'static str {
+    StatusCode::from_u16(code).ok().and_then(|status| status.canonical_reason()).unwrap_or("Unknown Status")
+}
+
+// Explains the statuses users run into the most
+fn status_description(code: u16) -> &'static str {
+    match code {
+        400 => ", the server cannot or will not process the request due to something that is perceived to be a client error.",
+        401 => ", the request has not been applied because it lacks valid authentication credentials for the target resource.",
+        403 => ", the server understood the request, but it refuses to authorize it.",
+        404 => ", the server has not found anything matching the Request-URI.",
+        500 => ", the server encountered an unexpected condition which prevented it from fulfilling the request.",
+        _ => "",
+    }
+}
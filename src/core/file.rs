@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::StreamExt;
+use iri_string::types::IriString;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH};
+use reqwest::Url;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio_util::io::ReaderStream;
+
+use crate::core::backend::{download_sequentially, DownloadBackend, Segment};
+use crate::core::config::Config;
+use crate::core::errors::RawstErr;
+use crate::core::progress::DownloadProgress;
+use crate::core::rate_limiter::RateLimiter;
+use crate::core::task::HttpTask;
+
+// Bytes read from the source at a time
+const READ_SIZE: usize = 64 * 1024;
+
+/// Copies `file://` URLs, for testing and for files already cached locally
+#[derive(Clone, Default)]
+pub struct FileBackend {
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl FileBackend {
+    pub fn new(rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        FileBackend { rate_limiter }
+    }
+}
+
+impl DownloadBackend for FileBackend {
+    async fn fetch_meta(
+        &self,
+        iri: &IriString,
+        _additional_headers: &HashMap<String, String>,
+    ) -> Result<(HeaderMap, IriString), RawstErr> {
+        let metadata = tokio::fs::metadata(local_path(iri)?).await.map_err(RawstErr::FileError)?;
+        if !metadata.is_file() {
+            return Err(RawstErr::FileError(io::Error::new(io::ErrorKind::InvalidInput, format!("{iri} is not a file"))));
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(metadata.len()));
+        // Copying is quick enough, segments would only compete for the same disk
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("none"));
+
+        Ok((headers, iri.clone()))
+    }
+
+    async fn segment(
+        &self,
+        iri: &IriString,
+        _additional_headers: &HashMap<String, String>,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Segment, RawstErr> {
+        let mut file = File::open(local_path(iri)?).await.map_err(RawstErr::FileError)?;
+        file.seek(SeekFrom::Start(start)).await.map_err(RawstErr::FileError)?;
+
+        let length = match end {
+            Some(end) => end + 1 - start,
+            None => u64::MAX,
+        };
+
+        let reader = BufReader::with_capacity(READ_SIZE, file).take(length);
+
+        Ok(ReaderStream::with_capacity(reader, READ_SIZE).map(|chunk| chunk.map_err(RawstErr::FileError)).boxed())
+    }
+
+    async fn download(
+        &self,
+        task: &HttpTask,
+        progress: &dyn DownloadProgress,
+        config: &Config,
+    ) -> Result<(), RawstErr> {
+        download_sequentially(self, task, progress, config, self.rate_limiter.as_deref()).await
+    }
+}
+
+fn local_path(iri: &IriString) -> Result<PathBuf, RawstErr> {
+    Url::parse(iri.as_str())
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| RawstErr::FileError(io::Error::new(io::ErrorKind::InvalidInput, format!("{iri} is not a local path"))))
+}
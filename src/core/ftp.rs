@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::stream::{self, StreamExt};
+use iri_string::types::IriString;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH};
+use suppaftp::tokio::AsyncFtpStream;
+use suppaftp::types::FileType;
+use tokio::io::AsyncReadExt;
+
+use crate::core::backend::{truncate, DownloadBackend, Segment};
+use crate::core::config::Config;
+use crate::core::errors::RawstErr;
+use crate::core::io::{finalize_file, partial_download_path, save_stream};
+use crate::core::progress::DownloadProgress;
+use crate::core::rate_limiter::RateLimiter;
+use crate::core::task::HttpTask;
+use crate::core::utils::percent_decode;
+
+const DEFAULT_PORT: u16 = 21;
+
+// Bytes read from the data connection at a time
+const READ_SIZE: usize = 64 * 1024;
+
+/// Downloads `ftp://` URLs over a single connection
+///
+/// Logs in with the credentials of the URL, anonymously otherwise. The size
+/// comes from `SIZE` and interrupted downloads continue with `REST`.
+#[derive(Clone)]
+pub struct FtpBackend {
+    connect_timeout: Duration,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl FtpBackend {
+    pub fn new(config: &Config, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        FtpBackend {
+            connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+            rate_limiter,
+        }
+    }
+
+    // Logged in and in binary mode, ready for SIZE and RETR
+    async fn connect(&self, iri: &IriString) -> Result<AsyncFtpStream, RawstErr> {
+        let authority = iri.authority_components().ok_or(RawstErr::Unreachable)?;
+        let port = authority.port().and_then(|port| port.parse().ok()).unwrap_or(DEFAULT_PORT);
+        let address = format!("{}:{}", authority.host(), port);
+
+        let mut ftp = tokio::time::timeout(self.connect_timeout, AsyncFtpStream::connect(address))
+            .await
+            .map_err(|_| RawstErr::Timeout)?
+            .map_err(|_| RawstErr::Unreachable)?;
+
+        let (user, password) = match authority.userinfo() {
+            Some(userinfo) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (decode(user), decode(password))
+            }
+            None => ("anonymous".to_string(), "anonymous@".to_string()),
+        };
+        ftp.login(user, password).await.map_err(RawstErr::FtpError)?;
+        ftp.transfer_type(FileType::Binary).await.map_err(RawstErr::FtpError)?;
+
+        Ok(ftp)
+    }
+}
+
+impl DownloadBackend for FtpBackend {
+    async fn fetch_meta(
+        &self,
+        iri: &IriString,
+        _additional_headers: &HashMap<String, String>,
+    ) -> Result<(HeaderMap, IriString), RawstErr> {
+        let mut ftp = self.connect(iri).await?;
+
+        let mut headers = HeaderMap::new();
+        // SIZE is an extension, without it the download goes on without a known size
+        match ftp.size(remote_path(iri)).await {
+            Ok(size) => {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(size as u64));
+            }
+            Err(err) => log::debug!("SIZE failed for {iri}: {err}"),
+        }
+        // Resumable with REST, but segments would each need their own login
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("none"));
+
+        let _ = ftp.quit().await;
+
+        Ok((headers, iri.clone()))
+    }
+
+    async fn segment(
+        &self,
+        iri: &IriString,
+        _additional_headers: &HashMap<String, String>,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Segment, RawstErr> {
+        let (segment, offset) = self.open(iri, start).await?;
+        if offset != start {
+            return Err(RawstErr::RangeIgnored(iri.to_string()));
+        }
+
+        Ok(match end {
+            Some(end) => truncate(segment, end + 1 - start),
+            None => segment,
+        })
+    }
+
+    async fn download(
+        &self,
+        task: &HttpTask,
+        progress: &dyn DownloadProgress,
+        config: &Config,
+    ) -> Result<(), RawstErr> {
+        let downloaded = task.total_downloaded.load(Ordering::SeqCst);
+        let (segment, offset) = self.open(&task.iri, downloaded).await?;
+        if offset != downloaded {
+            task.total_downloaded.store(0, Ordering::SeqCst);
+            progress.set_position(0);
+        }
+
+        let partial_path = partial_download_path(task, &config.download_dir);
+        save_stream(task, segment, offset > 0, progress, &partial_path, self.rate_limiter.as_deref(), config.buffer_size).await?;
+
+        finalize_file(task, &partial_path, &config.download_dir.join(&task.filename)).await
+    }
+}
+
+impl FtpBackend {
+    // Sends `iri` from `offset`, or from the start when the server refuses REST
+    async fn open(&self, iri: &IriString, offset: u64) -> Result<(Segment, u64), RawstErr> {
+        let mut ftp = self.connect(iri).await?;
+
+        let mut offset = offset;
+        if offset > 0 {
+            if let Err(err) = ftp.resume_transfer(offset as usize).await {
+                log::warn!("Server refused REST, sending {iri} from the start: {err}");
+                offset = 0;
+            }
+        }
+
+        let transfer = ftp.retr_as_stream(remote_path(iri)).await.map_err(RawstErr::FtpError)?;
+
+        let segment = stream::try_unfold((ftp, transfer), |(mut ftp, mut transfer)| async move {
+            let mut buffer = BytesMut::with_capacity(READ_SIZE);
+            if transfer.read_buf(&mut buffer).await.map_err(RawstErr::FileError)? == 0 {
+                // Reads the reply telling whether the whole file was sent
+                transfer.finish().await.map_err(RawstErr::FtpError)?;
+                let _ = ftp.quit().await;
+
+                return Ok(None);
+            }
+
+            Ok(Some((buffer.freeze(), (ftp, transfer))))
+        });
+
+        Ok((segment.boxed(), offset))
+    }
+}
+
+fn remote_path(iri: &IriString) -> String {
+    decode(iri.path_str())
+}
+
+fn decode(s: &str) -> String {
+    String::from_utf8_lossy(&percent_decode(s)).into_owned()
+}
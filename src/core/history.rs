@@ -1,10 +1,16 @@
 use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, Local};
 
 use iri_string::types::IriString;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use reqwest::header::{ETAG, LAST_MODIFIED};
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::cli::args::HistoryArgs;
 use crate::core::config::Config;
@@ -15,24 +21,23 @@ pub async fn check_history_args(args: HistoryArgs, config: Config) -> Result<(),
 
     let history_manager = HistoryManager::new(config.history_file_path);
 
-    if args.show {
-        history_manager.get_history()
-
-    } else if args.clear {
+    if args.clear {
         history_manager.clear_history()
-        
+
+    } else if let Some(export_path) = &args.export {
+        let exported = history_manager.export_csv(export_path)?;
+        println!("Exported {} records to {}", exported, export_path.display());
+
+        Ok(())
+
     } else {
-        Err(RawstErr::InvalidArgs)
+        // Listing is the default, `--show` is kept for older scripts
+        history_manager.get_history(&args)
 
     }
 
 }
 
-#[derive(Deserialize, Serialize)]
-struct Downloads {
-    record: Vec<Record>,
-}
-
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Record {
     pub id: String,
@@ -43,10 +48,21 @@ pub struct Record {
     pub threads_used: usize,
     pub timestamp: String,
     pub status: String,
-    pub headers: HashMap<String, String>
+    pub headers: HashMap<String, String>,
+    // Older history files don't have the fields below
+    #[serde(default)]
+    pub finished_at: Option<String>,
+    #[serde(default)]
+    pub checksum: Option<String>,
+    // Validators of the downloaded version, for `--conditional`
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
 }
 
 impl Record {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         iri: IriString,
@@ -55,7 +71,8 @@ impl Record {
         file_location: PathBuf,
         threads_used: usize,
         timestamp: String,
-        headers_used: HashMap<String, String>
+        headers_used: HashMap<String, String>,
+        checksum: Option<String>
     ) -> Record {
         
         Record {
@@ -68,10 +85,53 @@ impl Record {
             timestamp,
             status: "Pending".to_string(),
             headers: headers_used,
+            finished_at: None,
+            checksum,
+            etag: None,
+            last_modified: None,
         }
     }
+
+    pub fn started_at(&self) -> Option<DateTime<Local>> {
+        DateTime::from_str(&self.timestamp).ok()
+    }
+
+    /// Whether the record passes the `rawst history` filters
+    pub fn matches(&self, args: &HistoryArgs) -> bool {
+        if let Some(status) = &args.status {
+            if !self.status.eq_ignore_ascii_case(status) {
+                return false;
+            }
+        }
+
+        if let Some(since) = args.since {
+            match self.started_at() {
+                Some(started_at) if started_at.date_naive() >= since => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(text) = &args.url_contains {
+            if !self.iri.as_str().contains(text.as_str()) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Where the downloaded file was saved
+    pub fn file_path(&self) -> PathBuf {
+        self.file_location.join(&self.file_name)
+    }
+
+    /// Whether the download was interrupted and its cached chunks can be picked up again
+    pub fn is_resumable(&self) -> bool {
+        self.status == "Pending" || self.status == "Failed"
+    }
 }
 
+#[derive(Clone)]
 pub struct HistoryManager {
     pub file_path: PathBuf,
 }
@@ -81,24 +141,34 @@ impl HistoryManager {
         HistoryManager { file_path }
     }
 
-    pub fn add_record(&self, task: &HttpTask, config: &Config, id: String) -> Result<(), RawstErr> {
-        // TODO: Using jsonl would mean we can simply append to the history file.
-        let json_str: String = fs::read_to_string(&self.file_path).unwrap_or_else(|_| {
-            panic!(
-                "Couldn't read history database at '{}'.",
-                self.file_path.display()
-            )
-        });
+    /// Reads all the records, a missing or empty history file has none
+    pub fn load(&self) -> Result<Vec<Record>, RawstErr> {
+        let json_str = match fs::read_to_string(&self.file_path) {
+            Ok(json_str) => json_str,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(RawstErr::FileError(err)),
+        };
 
-        let mut records: Vec<Record> = serde_json::from_str(&json_str).unwrap_or_else(|_| {
-            panic!(
-                "Couldn't parse history database at '{}'.",
-                self.file_path.display()
-            )
-        });
+        if json_str.trim().is_empty() {
+            return Ok(Vec::new());
+        }
 
-        let new_record = Record::new(
-            id,
+        serde_json::from_str(&json_str)
+            .map_err(|err| RawstErr::HistoryParse(format!("{}: {}", self.file_path.display(), err)))
+    }
+
+    /// Adds `record` to the end of the history
+    pub fn append(&self, record: Record) -> Result<(), RawstErr> {
+        self.modify(|records| records.push(record))
+    }
+
+    /// Records a new download, returning the id it was recorded under
+    ///
+    /// Ids are only unique per second, so an `id` another download already has,
+    /// eg. one started by another process, gets a `-2`, `-3`, ... suffix.
+    pub fn add_record(&self, task: &HttpTask, config: &Config, id: String) -> Result<String, RawstErr> {
+        let mut new_record = Record::new(
+            id.clone(),
             task.iri.clone(),
             task.filename.clone(),
             task.content_length(),
@@ -106,81 +176,159 @@ impl HistoryManager {
             config.threads,
             task.timestamp.to_string(),
             task.additional_headers.clone(),
+            task.checksum.as_ref().map(|checksum| checksum.to_string()),
         );
+        new_record.etag = task.response_header(ETAG.as_str()).map(str::to_string);
+        new_record.last_modified = task.response_header(LAST_MODIFIED.as_str()).map(str::to_string);
+
+        let mut recorded_id = String::new();
+        self.modify(|records| {
+            let taken = |candidate: &String| records.iter().any(|record| record.id == *candidate);
+            recorded_id = std::iter::once(id.clone())
+                .chain((2..).map(|n| format!("{}-{}", id, n)))
+                .find(|candidate| !taken(candidate))
+                .unwrap();
+
+            new_record.id = recorded_id.clone();
+            records.push(new_record);
+        })?;
+
+        Ok(recorded_id)
+    }
 
-        records.push(new_record);
+    /// Marks the record as "Completed" or "Failed" and stamps when it finished
+    ///
+    /// `checksum` replaces the recorded one when given.
+    pub fn update_record(&self, id: String, succeeded: bool, checksum: Option<String>) -> Result<(), RawstErr> {
+        let status = if succeeded { "Completed" } else { "Failed" };
+        let finished_at = Local::now().to_string();
+
+        // Ids are only unique per second, the latest record is the one being updated
+        self.modify(|records| {
+            if let Some(record) = records.iter_mut().rev().find(|record| record.id == id) {
+                record.status = status.to_string();
+                record.finished_at = Some(finished_at);
+                if checksum.is_some() {
+                    record.checksum = checksum;
+                }
+            }
+        })
+    }
+
+    /// Read-modify-write of the history file
+    ///
+    /// Holds an exclusive lock on a sibling `.lock` file so concurrent rawst
+    /// processes take turns, and swaps the new contents in with a rename so
+    /// readers never see a half written file.
+    fn modify<F>(&self, change: F) -> Result<(), RawstErr>
+    where
+        F: FnOnce(&mut Vec<Record>),
+    {
+        let lock_file = File::create(self.file_path.with_extension("json.lock")).map_err(RawstErr::FileError)?;
+        lock_file.lock().map_err(RawstErr::FileError)?;
+
+        let mut records = self.load()?;
+        change(&mut records);
 
         let new_json_str = serde_json::to_string_pretty(&records).unwrap();
 
-        fs::write(&self.file_path, new_json_str).map_err(RawstErr::FileError)?;
+        let temp_path = self.file_path.with_extension("json.tmp");
+        fs::write(&temp_path, new_json_str).map_err(RawstErr::FileError)?;
+        fs::rename(&temp_path, &self.file_path).map_err(RawstErr::FileError)?;
 
+        // The lock is released when lock_file is dropped
         Ok(())
     }
 
-    pub fn update_record(&self, id: String) -> Result<(), RawstErr> {
-        let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
-
-        let mut records: Vec<Record> =
-            serde_json::from_str(&json_str).expect("There are no downloads");
-
-        for record in records.iter_mut() {
-            if record.id == id {
-                record.status = "Completed".to_string();
+    /// Writes every record to `path` as CSV, returning how many were written
+    ///
+    /// Records are deserialized and written one at a time, so the history is
+    /// never held in memory as a whole. A missing or empty history file only
+    /// gets the header.
+    pub fn export_csv(&self, path: &Path) -> Result<u64, RawstErr> {
+        // None when there are no records to read
+        let history_file = match File::open(&self.file_path) {
+            Ok(file) => {
+                let mut reader = io::BufReader::new(file);
+                match is_blank(&mut reader).map_err(RawstErr::FileError)? {
+                    true => None,
+                    false => Some(reader),
+                }
             }
-        }
-
-        let new_json_str = serde_json::to_string_pretty(&records).unwrap();
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(RawstErr::FileError(err)),
+        };
+        let mut writer = csv::Writer::from_path(path).map_err(csv_error)?;
+
+        writer
+            .write_record(["url", "filename", "bytes", "started", "finished", "status"])
+            .map_err(csv_error)?;
+
+        let exported = match history_file {
+            Some(reader) => {
+                let mut deserializer = serde_json::Deserializer::from_reader(reader);
+                deserializer
+                    .deserialize_seq(CsvExporter { writer: &mut writer })
+                    .map_err(|err| RawstErr::HistoryParse(format!("{}: {}", self.file_path.display(), err)))?
+            }
+            None => 0,
+        };
 
-        fs::write(&self.file_path, new_json_str).map_err(RawstErr::FileError)?;
+        writer.flush().map_err(RawstErr::FileError)?;
 
-        Ok(())
+        Ok(exported)
     }
 
     pub fn clear_history(&self) -> Result<(), RawstErr> {
-        fs::write(&self.file_path, "[\n\n]").map_err(RawstErr::FileError)?;
+        // Also takes the lock, a download finishing meanwhile would otherwise bring the old records back
+        self.modify(|records| records.clear())?;
         println!("History cleared!");
 
         Ok(())
 
     }
 
-    pub fn get_history(&self) -> Result<(), RawstErr> {
-        let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
+    pub fn get_history(&self, args: &HistoryArgs) -> Result<(), RawstErr> {
+        let records = self.load()?;
 
-        let value: Value = serde_json::from_str(&json_str).expect("There are no downloads");
+        if records.is_empty() && !args.json {
+            println!("No downloads yet");
+            return Ok(());
+        }
 
-        let mut result = Vec::new();
+        let matching: Vec<&Record> = records.iter().filter(|record| record.matches(args)).collect();
 
-        match value {
-            Value::Array(arr) => {
-                for item in arr.iter() {
-                    let record = serde_json::from_value::<Record>(item.clone());
-                    if let Ok(r) = record {
-                        result.push(r);
-                    }
-                }
-            }
-            _ => {
-                println!("Expected an array");
-                return Ok(());
-            }
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&matching).unwrap());
+            return Ok(());
+        }
+
+        if matching.is_empty() {
+            println!("No downloads match the filters");
+            return Ok(());
         }
 
-        for record in result.iter() {
-            println!("\nRecord\nid: {}\niri: {}\nfile name: {}\nfile size: {:?} bytes\nfile location: {}\nthreads used: {:?}\ntimestamp: {}\nstatus: {}\nheaders: {:?}",
-            record.id, record.iri, record.file_name.display(), record.file_size, record.file_location.display(), record.threads_used, record.timestamp, record.status, record.headers);
+        println!("{:<14} {:<9} {:<19} {:>12}  {:<24} URL", "ID", "STATUS", "STARTED", "SIZE", "FILE");
+        for record in matching {
+            println!(
+                "{:<14} {:<9} {:<19} {:>12}  {:<24} {}",
+                record.id,
+                record.status,
+                record.started_at().map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default(),
+                record.file_size,
+                record.file_name.display(),
+                record.iri
+            );
         }
 
         Ok(())
     }
 
     pub fn get_recent_pending(&self) -> Result<Option<Record>, RawstErr> {
-        let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
-
-        let records: Vec<Record> = serde_json::from_str(&json_str).expect("There are no downloads");
+        let records = self.load()?;
 
         for record in records.iter().rev() {
-            if record.status == "Pending" {
+            if record.is_resumable() {
                 return Ok(Some(record.to_owned()));
             }
         }
@@ -188,10 +336,29 @@ impl HistoryManager {
         Ok(None)
     }
 
-    pub fn get_record(&self, id: &String) -> Result<Option<Record>, RawstErr> {
-        let json_str = fs::read_to_string(&self.file_path).map_err(RawstErr::FileError)?;
+    /// Most recent completed download of `url`
+    pub fn find_completed(&self, url: &str) -> Result<Option<Record>, RawstErr> {
+        let records = self.load()?;
+
+        Ok(records
+            .into_iter()
+            .rev()
+            .find(|record| record.status == "Completed" && record.iri.as_str() == url))
+    }
 
-        let records: Vec<Record> = serde_json::from_str(&json_str).expect("There are no downloads");
+    /// Most recent completed download saved at `path`, or else saved under its file name
+    pub fn find_by_file(&self, path: &Path) -> Result<Option<Record>, RawstErr> {
+        let records = self.load()?;
+        let completed = || records.iter().rev().filter(|record| record.status == "Completed");
+
+        let by_path = completed().find(|record| record.file_path() == path);
+        let by_name = || completed().find(|record| Some(record.file_name.as_os_str()) == path.file_name());
+
+        Ok(by_path.or_else(by_name).cloned())
+    }
+
+    pub fn get_record(&self, id: &String) -> Result<Option<Record>, RawstErr> {
+        let records = self.load()?;
 
         for record in records {
             if record.id == *id {
@@ -202,3 +369,229 @@ impl HistoryManager {
         Ok(None)
     }
 }
+
+fn csv_error(err: csv::Error) -> RawstErr {
+    RawstErr::FileError(err.into())
+}
+
+// Skips the leading whitespace of `reader`, telling whether that was all there was
+fn is_blank(reader: &mut impl BufRead) -> io::Result<bool> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(true);
+        }
+
+        let blank = buffer.iter().take_while(|byte| byte.is_ascii_whitespace()).count();
+        let filled = buffer.len();
+        reader.consume(blank);
+        if blank < filled {
+            return Ok(false);
+        }
+    }
+}
+
+// Visits the history array writing each record as soon as it's parsed
+struct CsvExporter<'a, W: io::Write> {
+    writer: &'a mut csv::Writer<W>,
+}
+
+impl<'de, W: io::Write> Visitor<'de> for CsvExporter<'_, W> {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of history records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<u64, A::Error> {
+        let mut exported = 0;
+
+        while let Some(record) = seq.next_element::<Record>()? {
+            self.writer
+                .write_record([
+                    record.iri.as_str(),
+                    &record.file_name.display().to_string(),
+                    &record.file_size.to_string(),
+                    &record.timestamp,
+                    record.finished_at.as_deref().unwrap_or(""),
+                    &record.status,
+                ])
+                .map_err(serde::de::Error::custom)?;
+
+            exported += 1;
+        }
+
+        Ok(exported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::testing::TempDir;
+
+    fn record(id: &str, url: &str, file_name: &str) -> Record {
+        Record::new(
+            id.to_string(),
+            IriString::try_from(url).unwrap(),
+            PathBuf::from(file_name),
+            1024,
+            PathBuf::from("/srv/downloads"),
+            4,
+            "2024-12-31 23:59:59.000000 +01:00".to_string(),
+            HashMap::new(),
+            None,
+        )
+    }
+
+    #[test]
+    fn exported_csv_reads_back_the_same_records() {
+        let dir = TempDir::new();
+        let history = HistoryManager::new(dir.path().join("history.json"));
+        let mut finished = record("1", "http://example.com/a,b.txt", "a,b.txt");
+        finished.status = "Completed".to_string();
+        finished.finished_at = Some("2025-01-01 00:00:10.000000 +01:00".to_string());
+        history.append(finished).unwrap();
+        history.append(record("2", "http://example.com/quote", "say \"cheese\".txt")).unwrap();
+
+        let csv_path = dir.path().join("history.csv");
+        assert_eq!(history.export_csv(&csv_path).unwrap(), 2);
+
+        let mut reader = csv::Reader::from_path(&csv_path).unwrap();
+        assert_eq!(reader.headers().unwrap(), vec!["url", "filename", "bytes", "started", "finished", "status"]);
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows, vec![
+            csv::StringRecord::from(vec![
+                "http://example.com/a,b.txt",
+                "a,b.txt",
+                "1024",
+                "2024-12-31 23:59:59.000000 +01:00",
+                "2025-01-01 00:00:10.000000 +01:00",
+                "Completed",
+            ]),
+            csv::StringRecord::from(vec![
+                "http://example.com/quote",
+                "say \"cheese\".txt",
+                "1024",
+                "2024-12-31 23:59:59.000000 +01:00",
+                "",
+                "Pending",
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn an_empty_history_exports_only_the_header() {
+        let dir = TempDir::new();
+        let history = HistoryManager::new(dir.path().join("history.json"));
+        fs::write(&history.file_path, "[\n\n]").unwrap();
+
+        let csv_path = dir.path().join("history.csv");
+        assert_eq!(history.export_csv(&csv_path).unwrap(), 0);
+        assert_eq!(fs::read_to_string(&csv_path).unwrap(), "url,filename,bytes,started,finished,status\n");
+    }
+
+    #[test]
+    fn missing_and_blank_histories_export_only_the_header() {
+        let dir = TempDir::new();
+        let history = HistoryManager::new(dir.path().join("history.json"));
+        let csv_path = dir.path().join("history.csv");
+
+        assert_eq!(history.export_csv(&csv_path).unwrap(), 0);
+        assert_eq!(fs::read_to_string(&csv_path).unwrap(), "url,filename,bytes,started,finished,status\n");
+
+        for blank in ["", "  \n"] {
+            fs::write(&history.file_path, blank).unwrap();
+            assert_eq!(history.export_csv(&csv_path).unwrap(), 0, "{:?} should have no records", blank);
+            assert_eq!(fs::read_to_string(&csv_path).unwrap(), "url,filename,bytes,started,finished,status\n");
+        }
+    }
+
+    #[test]
+    fn missing_and_empty_histories_have_no_records() {
+        let dir = TempDir::new();
+        let history = HistoryManager::new(dir.path().join("history.json"));
+        assert!(history.load().unwrap().is_empty());
+
+        for empty in ["", "  \n", "[\n\n]"] {
+            fs::write(&history.file_path, empty).unwrap();
+            assert!(history.load().unwrap().is_empty(), "{:?} should have no records", empty);
+        }
+    }
+
+    #[test]
+    fn records_are_appended_to_an_empty_history() {
+        let dir = TempDir::new();
+        let history = HistoryManager::new(dir.path().join("history.json"));
+        fs::write(&history.file_path, "[\n\n]").unwrap();
+
+        history.append(record("1", "http://example.com/a", "a")).unwrap();
+
+        let records = history.load().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "1");
+        assert_eq!(records[0].status, "Pending");
+    }
+
+    #[test]
+    fn records_are_appended_after_the_existing_ones() {
+        let dir = TempDir::new();
+        let history = HistoryManager::new(dir.path().join("history.json"));
+        history.append(record("1", "http://example.com/a", "a")).unwrap();
+        history.append(record("2", "http://example.com/b", "b")).unwrap();
+
+        history.append(record("3", "http://example.com/c", "c")).unwrap();
+
+        let records = history.load().unwrap();
+        let ids: Vec<&str> = records.iter().map(|record| record.id.as_str()).collect();
+        assert_eq!(ids, ["1", "2", "3"]);
+        assert_eq!(records[2].iri.as_str(), "http://example.com/c");
+        assert_eq!(records[2].file_name, PathBuf::from("c"));
+    }
+
+    #[test]
+    fn a_corrupt_history_is_reported() {
+        let dir = TempDir::new();
+        let history = HistoryManager::new(dir.path().join("history.json"));
+        fs::write(&history.file_path, "[{\"id\": ").unwrap();
+
+        assert!(matches!(history.load(), Err(RawstErr::HistoryParse(_))));
+        assert!(history.append(record("1", "http://example.com/a", "a")).is_err());
+        // Left as it was for the user to fix
+        assert_eq!(fs::read_to_string(&history.file_path).unwrap(), "[{\"id\": ");
+    }
+
+    #[test]
+    fn concurrent_appends_keep_every_record() {
+        const WRITERS: usize = 8;
+        const RECORDS_PER_WRITER: usize = 25;
+
+        let dir = TempDir::new();
+        let file_path = dir.path().join("history.json");
+
+        std::thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                // A manager each, like separate rawst processes
+                let history = HistoryManager::new(file_path.clone());
+                scope.spawn(move || {
+                    for n in 0..RECORDS_PER_WRITER {
+                        let id = format!("{}-{}", writer, n);
+                        history.append(record(&id, "http://example.com/file", &id)).unwrap();
+                    }
+                });
+            }
+        });
+
+        let json_str = fs::read_to_string(&file_path).unwrap();
+        let records: Vec<Record> = serde_json::from_str(&json_str).unwrap();
+        assert_eq!(records.len(), WRITERS * RECORDS_PER_WRITER);
+
+        let mut ids: Vec<String> = records.into_iter().map(|record| record.id).collect();
+        ids.sort();
+        let mut expected: Vec<String> = (0..WRITERS)
+            .flat_map(|writer| (0..RECORDS_PER_WRITER).map(move |n| format!("{}-{}", writer, n)))
+            .collect();
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+}
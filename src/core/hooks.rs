@@ -0,0 +1,102 @@
+use std::path::Path;
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+use crate::core::errors::RawstErr;
+use crate::core::output;
+
+/// Commands run after each download, given with `--on-complete` and `--on-error`
+///
+/// They run through the shell, `sh -c` or `cmd /C` on Windows, so they can use
+/// pipes and redirections. The command itself is trusted as much as any
+/// other line typed in a shell. The values it is given come from the
+/// download and are quoted before being put in, as file names can be
+/// chosen by the server.
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    pub on_complete: Option<String>,
+    pub on_error: Option<String>,
+}
+
+impl Hooks {
+    /// Runs `on_complete` for the file at `path`, downloaded from `url`
+    pub async fn completed(&self, path: &Path, url: &str) {
+        if let Some(command) = &self.on_complete {
+            run(&expand(command, path, url, ""), "--on-complete").await;
+        }
+    }
+
+    /// Runs `on_error` for a download that failed with `err`
+    ///
+    /// `path` is empty when the download failed before its output was known.
+    pub async fn failed(&self, path: &Path, url: &str, err: &RawstErr) {
+        if let Some(command) = &self.on_error {
+            run(&expand(command, path, url, &err.to_string()), "--on-error").await;
+        }
+    }
+}
+
+/// Replaces `%f` with the path, `%u` with the URL, `%e` with the error and `%%` with `%`
+pub fn expand(command: &str, path: &Path, url: &str, error: &str) -> String {
+    let mut expanded = String::with_capacity(command.len());
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('f') => expanded.push_str(&shell_quote(&path.to_string_lossy())),
+            Some('u') => expanded.push_str(&shell_quote(url)),
+            Some('e') => expanded.push_str(&shell_quote(error)),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+
+    expanded
+}
+
+// A single word for the shell, whatever `value` holds
+#[cfg(not(windows))]
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(windows)]
+fn shell_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+// Shell running the hooks and its flag for taking a command
+#[cfg(not(windows))]
+const SHELL: (&str, &str) = ("sh", "-c");
+#[cfg(windows)]
+const SHELL: (&str, &str) = ("cmd", "/C");
+
+// Waits for `command`, a failing hook doesn't change the outcome of the download
+async fn run(command: &str, hook: &str) {
+    log::info!("Running {} hook: {}", hook, command);
+
+    let (shell, flag) = SHELL;
+    let status = Command::new(shell).arg(flag).arg(command).stdin(Stdio::null()).status().await;
+
+    match status {
+        Ok(status) if status.success() => log::info!("{} hook exited with {}", hook, status),
+        Ok(status) => {
+            log::warn!("{} hook exited with {}", hook, status);
+            output::status(format!("Warning!: {} hook exited with {}", hook, status));
+        }
+        Err(err) => {
+            log::warn!("Couldn't run the {} hook: {}", hook, err);
+            output::status(format!("Warning!: Couldn't run the {} hook: {}", hook, err));
+        }
+    }
+}
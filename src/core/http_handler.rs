@@ -1,74 +1,198 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::stream::{self, StreamExt};
-use indicatif::ProgressBar;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use iri_string::types::IriString;
+use reqwest::cookie::Jar;
 use reqwest::{
-    header::{HeaderMap, HeaderValue, RANGE},
-    Client, StatusCode, ClientBuilder,
+    header::{HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, IF_MODIFIED_SINCE, IF_NONE_MATCH, RANGE, RETRY_AFTER},
+    redirect, Certificate, Client, StatusCode, ClientBuilder, NoProxy, Proxy, Response,
 };
 
-use crate::core::config::Config;
+use crate::core::backend::{DownloadBackend, Scheme, Segment};
+use crate::core::checksum::ChecksumSource;
+use crate::core::config::{Config, HttpVersion, ProxyUrl, RetryPolicy};
 use crate::core::errors::RawstErr;
-use crate::core::io::{create_cache, create_file, merge_files};
-use crate::core::task::{ChunkType, HttpTask};
+use crate::core::interrupt::is_interrupted;
+use crate::core::progress::DownloadProgress;
+use crate::core::io::{create_cache, create_file, Checksum, finalize_file, merge_files, merge_files_positioned, preallocate_output, remove_chunks, save_stream, write_in_place, writes_in_place, Output};
+use crate::core::rate_limiter::{HostLimiter, RateLimiter};
+use tokio::fs::remove_file;
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::Instrument;
+use crate::core::task::{ByteRange, ChunkType, HttpTask, SegmentStats};
+use crate::core::utils::{chunk_file_name, extract_filename_from_url};
 
-#[derive(Clone, Default)]
+// Longest wait a server can ask for with Retry-After
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+// Sent unless the config says otherwise
+const DEFAULT_USER_AGENT: &str = concat!("rawst/", env!("CARGO_PKG_VERSION"));
+
+/// Issues every request of a download
+///
+/// The client is built once in [`HttpHandler::new`] and borrowed by all the
+/// segment tasks, so they share its connection pool and TLS sessions.
+/// `reqwest::Client` is reference counted, cloning the handler keeps sharing it.
+#[derive(Clone)]
 pub struct HttpHandler {
     pub client: Client,
+    // Shared across all segments so the limit applies to the whole download
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub host_limiter: Option<Arc<HostLimiter>>,
+    buffer_size: usize,
 }
 
 impl HttpHandler {
-    pub fn new() -> Self {
-        let client = ClientBuilder::new().cookie_store(true).build().unwrap();
+    pub fn new(config: &Config) -> Result<Self, RawstErr> {
+        Self::with_cookies(config, Arc::new(Jar::default()))
+    }
 
-        Self {
-            client,
+    /// Shares `rate_limiter` with other handlers and backends instead of limiting this one on its own
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Builds the handler around a cookie jar prepared by the caller
+    ///
+    /// Cookies set by responses, redirects included, are added to the same jar.
+    /// Needs the `cookies` feature of reqwest.
+    pub fn with_cookies(config: &Config, cookies: Arc<Jar>) -> Result<Self, RawstErr> {
+        let mut client_builder = ClientBuilder::new()
+            .cookie_provider(cookies)
+            .user_agent(config.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .read_timeout(Duration::from_millis(config.read_timeout_ms))
+            // Redirected requests keep their Range header, only credentials are dropped across hosts
+            .redirect(redirect::Policy::limited(config.max_redirects))
+            // Compression is only asked for without a Range, which always gets the raw bytes
+            .gzip(config.decompress)
+            .deflate(config.decompress)
+            .brotli(config.decompress);
+
+        if let Some(request_timeout_ms) = config.request_timeout_ms {
+            client_builder = client_builder.timeout(Duration::from_millis(request_timeout_ms));
+        }
+
+        if let Some(proxy) = &config.proxy {
+            client_builder = client_builder.proxy(build_proxy(proxy)?);
+        }
+
+        if let Some(ca_cert) = &config.ca_cert {
+            for certificate in load_certificates(ca_cert)? {
+                client_builder = client_builder.add_root_certificate(certificate);
+            }
         }
+
+        if config.insecure {
+            client_builder = client_builder.danger_accept_invalid_certs(true);
+        }
+
+        client_builder = match config.http_version {
+            HttpVersion::Auto => client_builder,
+            HttpVersion::Http1 => client_builder.http1_only(),
+            HttpVersion::Http2 => client_builder.http2_prior_knowledge(),
+        };
+
+        // Segments are streams of the same connection, there's no connection to hold back
+        let max_connections_per_host = match config.http_version {
+            HttpVersion::Http2 => None,
+            _ => config.max_connections_per_host,
+        };
+
+        let client = client_builder
+            .build()
+            .map_err(|err| RawstErr::ConfigParse(format!("Couldn't build the HTTP client: {}", err)))?;
+
+        Ok(Self {
+            client,
+            rate_limiter: config.rate_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+            host_limiter: max_connections_per_host.map(|max| Arc::new(HostLimiter::new(max))),
+            buffer_size: config.buffer_size,
+        })
+    }
+
+    // Waits for a free connection to the host of `iri`, held until the permit is dropped
+    async fn connection_permit(&self, iri: &IriString) -> Option<OwnedSemaphorePermit> {
+        let host_limiter = self.host_limiter.as_ref()?;
+        let host = iri.authority_components().map(|authority| authority.host().to_string()).unwrap_or_default();
+
+        Some(host_limiter.acquire(&host).await)
     }
 
     pub async fn sequential_download(
         &self,
         task: &HttpTask,
-        progressbar: &ProgressBar,
+        progressbar: &dyn DownloadProgress,
         config: &Config,
+        output: &Output,
+    ) -> Result<(), RawstErr> {
+        log::trace!("Starting sequential download (task:{task:?}, config:{config:?}, output:{output:?})");
+
+        with_retries(config, "Sequential download", |attempt| {
+            self.fetch_single(task.source(attempt as usize - 1), task, progressbar, output)
+        }).await
+    }
+
+    async fn fetch_single(
+        &self,
+        source: &IriString,
+        task: &HttpTask,
+        progressbar: &dyn DownloadProgress,
+        output: &Output,
     ) -> Result<(), RawstErr> {
-        log::trace!("Starting sequential download (task:{task:?}, config:{config:?})");
         let mut headers: HeaderMap = (&task.additional_headers).try_into().expect("invalid headers");
 
-        if let ChunkType::Single(chunk) = &task.chunk_data {
-            let range_value = format!("bytes={}-{}", chunk.x_offset, chunk.y_offset);
+        // Only ask for the remaining bytes when resuming a partial file
+        let downloaded = task.total_downloaded.load(Ordering::SeqCst);
+        if downloaded > 0 {
+            let range_value = format!("bytes={}-", downloaded);
 
             headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
         }
 
+        let _permit = self.connection_permit(source).await;
         let response = self
             .client
-            .get(to_reqwest_url(&task.iri))
+            .get(to_reqwest_url(source))
             .headers(headers)
             .send()
             .await
-            .map_err(RawstErr::HttpError)?;
+            .map_err(RawstErr::from_http)?;
 
-        if response.status().is_success() {
-            create_file(task, response, progressbar, &config.download_dir).await?;
+        if !response.status().is_success() {
+            return Err(status_error(response));
         }
 
-        Ok(())
+        create_file(task, response, progressbar, output, self.rate_limiter.as_deref(), self.buffer_size)
+            .instrument(tracing::debug_span!("create_file", file = %task.filename.display()))
+            .await
     }
 
     pub async fn concurrent_download(
         &self,
         task: &HttpTask,
-        progressbar: &ProgressBar,
+        progressbar: &dyn DownloadProgress,
         config: &Config,
     ) -> Result<(), RawstErr> {
         log::trace!("Starting concurrent download (task:{task:?}, config:{config:?})");
+        // Segments go straight to their place in the output when its size is known
+        let in_place = match writes_in_place(task, config) {
+            true => Some(preallocate_output(task, config).await?),
+            false => None,
+        };
+        let in_place = in_place.as_deref();
+
         // Creates a stream iter for downloading each chunk separately
         let download_tasks = stream::iter((0..config.threads).map(|i| {
-            let client = &self.client;
-            let mut headers: HeaderMap = (&task.additional_headers).try_into().expect("invalid headers");
-
             // Creates closure for each request and IO operation
             // Each closure has separate IO operation
             async move {
@@ -79,67 +203,1097 @@ impl HttpHandler {
                         return Ok(())
                     }
 
-                    let range_value = format!("bytes={}-{}", chunks[i].x_offset, chunks[i].y_offset);
+                    if is_interrupted() {
+                        return Err(RawstErr::Interrupted);
+                    }
 
-                    headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
+                    let started = Instant::now();
+                    let resumed_at = chunks[i].downloaded.load(Ordering::SeqCst);
+                    let range = task.chunk_range(&chunks[i]);
+                    let span = tracing::debug_span!("segment", url = %task.iri, index = i, range = %range, bytes = tracing::field::Empty);
 
-                    let response = client
-                        .get(to_reqwest_url(&task.iri))
-                        .headers(headers)
-                        .send()
-                        .await
-                        .map_err(RawstErr::HttpError)?;
+                    // Chunks are spread over the mirrors, each retry moves to the next one
+                    with_retries(config, &format!("Chunk number {i}"), |attempt| {
+                        let source = task.source(i + attempt as usize - 1);
+                        self.fetch_chunk(i, source, task, progressbar, config, in_place)
+                    })
+                    .instrument(span.clone())
+                    .await?;
 
-                    if response.status().is_success() {
-                        create_cache(i, task, response, progressbar, &config.cache_dir).await?;
-                    }
+                    let bytes = chunks[i].downloaded.load(Ordering::SeqCst).saturating_sub(resumed_at);
+                    span.record("bytes", bytes);
+                    task.record_segment(SegmentStats {
+                        index: i,
+                        range,
+                        bytes,
+                        elapsed: started.elapsed(),
+                    });
                 }
 
                 Ok::<_, RawstErr>(())
             }
         }));
 
-        download_tasks
+        let result = download_tasks
             .buffer_unordered(config.threads)
             .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>();
+
+        // Nothing downloaded so far can be kept, it's of another version
+        if let Err(RawstErr::ResourceChanged(_)) = &result {
+            match in_place {
+                Some(partial_path) => remove_file(partial_path).await.map_err(RawstErr::FileError)?,
+                None => remove_chunks(task, config).await?,
+            }
+            task.total_downloaded.store(0, Ordering::SeqCst);
+        }
+        result?;
+
+        // Merged and in-place files get their final size up front, only the count tells a segment came up short
+        let downloaded = task.total_downloaded.load(Ordering::SeqCst);
+        if task.content_length() > 0 && downloaded != task.content_length() {
+            match in_place {
+                Some(partial_path) => remove_file(partial_path).await.map_err(RawstErr::FileError)?,
+                None => remove_chunks(task, config).await?,
+            }
+
+            return Err(RawstErr::SizeMismatch { expected: task.content_length(), actual: downloaded });
+        }
+
+        match in_place {
+            Some(partial_path) => finalize_file(task, partial_path, &config.download_dir.join(&task.filename)).await?,
+            // Chunks can be written in place when the final size is known
+            None if task.content_length() > 0 => {
+                merge_files_positioned(task, &task.chunk_offsets(), config).instrument(merge_span(task, config.threads)).await?
+            }
+            None => merge_files(task, config).instrument(merge_span(task, config.threads)).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Downloads a file of unknown size in segments found along the way, see [`DynamicSplit`]
+    ///
+    /// Falls back to a single stream when the server ignores a range. Segments
+    /// left by a failed attempt are removed, resuming starts over.
+    pub async fn dynamic_download(
+        &self,
+        task: &HttpTask,
+        progressbar: &dyn DownloadProgress,
+        config: &Config,
+    ) -> Result<(), RawstErr> {
+        log::trace!("Starting dynamic download (task:{task:?}, config:{config:?})");
+        let split = DynamicSplit::default();
+
+        let result = stream::iter(0..)
+            .take_while(|&i| future::ready(DynamicSplit::segment(i).0 < split.end()))
+            .map(|i| {
+                split.started.fetch_max(i + 1, Ordering::SeqCst);
+                self.fetch_dynamic_segment(i, task, progressbar, config, &split)
+                    .instrument(tracing::debug_span!("segment", url = %task.iri, index = i))
+            })
+            .buffer_unordered(config.threads)
+            .try_collect::<Vec<_>>()
             .await;
 
-        merge_files(task, config).await?;
+        let downloaded = task.total_downloaded.load(Ordering::SeqCst);
+        let started = split.started.load(Ordering::SeqCst);
+
+        let result = match result {
+            // The end was found once the segments stop
+            Ok(_) => {
+                let end = split.end();
+                let segments = (0..started).take_while(|&i| DynamicSplit::segment(i).0 < end).count();
+
+                match downloaded == end {
+                    true => Ok(segments),
+                    false => Err(RawstErr::SizeMismatch { expected: end, actual: downloaded }),
+                }
+            }
+            Err(err) => Err(err),
+        };
+
+        // Segments starting past the end were only needed to find it
+        let unused = match &result {
+            Ok(segments) => *segments..started,
+            Err(_) => 0..started,
+        };
+        for i in unused {
+            match remove_file(config.segment_dir().join(chunk_file_name(task.download_id(), i))).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(RawstErr::FileError(err)),
+                _ => {}
+            }
+        }
+
+        match result {
+            Ok(segments) => {
+                log::debug!("{} is {} bytes, downloaded in {} segments", task.iri, downloaded, segments);
+
+                let merged = Config { threads: segments, ..config.clone() };
+                merge_files(task, &merged).instrument(merge_span(task, segments)).await
+            }
+            Err(RawstErr::RangeIgnored(_)) => {
+                log::warn!("{} stopped honouring ranges, downloading it in a single stream", task.iri);
+                task.total_downloaded.store(0, Ordering::SeqCst);
+                progressbar.set_position(0);
+
+                self.sequential_download(task, progressbar, config, &Output::Path(config.download_dir.clone())).await
+            }
+            Err(err) => {
+                task.total_downloaded.store(0, Ordering::SeqCst);
+                Err(err)
+            }
+        }
+    }
+
+    // Downloads segment `i` of a dynamic split into its chunk file, continuing after what's there on retries
+    async fn fetch_dynamic_segment(
+        &self,
+        i: usize,
+        task: &HttpTask,
+        progressbar: &dyn DownloadProgress,
+        config: &Config,
+        split: &DynamicSplit,
+    ) -> Result<(), RawstErr> {
+        let (start, length) = DynamicSplit::segment(i);
+        let chunk_path = config.segment_dir().join(chunk_file_name(task.download_id(), i));
+        let chunk_path = &chunk_path;
+        let started = Instant::now();
+
+        with_retries(config, &format!("Segment number {i}"), |attempt| async move {
+            let written = tokio::fs::metadata(chunk_path).await.map(|metadata| metadata.len()).unwrap_or(0);
+            if written == length {
+                return Ok(());
+            }
+
+            let source = task.source(i + attempt as usize - 1);
+            match self.ranged_get(source, &task.additional_headers, start + written, Some(start + length - 1)).await {
+                Ok((response, permit)) => {
+                    task.check_segment(source, response.headers())?;
+
+                    let segment = body_stream(response, permit);
+                    save_stream(task, segment, written > 0, progressbar, chunk_path, self.rate_limiter.as_deref(), config.buffer_size).await?;
+
+                    // Only the last segment of the file comes back short
+                    let written = tokio::fs::metadata(chunk_path).await.map_err(RawstErr::FileError)?.len();
+                    if written < length {
+                        split.found_end(start + written);
+                    }
+
+                    Ok(())
+                }
+                // Starts past the end of the file
+                Err(RawstErr::HttpStatus { code: 416, .. }) => {
+                    split.found_end(start + written);
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            }
+        }).await?;
+
+        let bytes = tokio::fs::metadata(chunk_path).await.map(|metadata| metadata.len()).unwrap_or(0);
+        if bytes > 0 {
+            task.record_segment(SegmentStats {
+                index: i,
+                range: ByteRange { start, end: Some(start + bytes - 1) },
+                bytes,
+                elapsed: started.elapsed(),
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_chunk(
+        &self,
+        i: usize,
+        source: &IriString,
+        task: &HttpTask,
+        progressbar: &dyn DownloadProgress,
+        config: &Config,
+        in_place: Option<&Path>,
+    ) -> Result<(), RawstErr> {
+        if let ChunkType::Multiple(chunks) = &task.chunk_data {
+            let chunk = &chunks[i];
+            // Retries continue after the bytes already on disk
+            let start = chunk.start + chunk.downloaded.load(Ordering::SeqCst);
+
+            if start > chunk.y_offset {
+                return Ok(());
+            }
+
+            let mut headers: HeaderMap = (&task.additional_headers).try_into().expect("invalid headers");
+            let range_value = format!("bytes={}-{}", start, chunk.y_offset);
+
+            headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
+
+            let _permit = self.connection_permit(source).await;
+            let response = self
+                .client
+                .get(to_reqwest_url(source))
+                .headers(headers)
+                .send()
+                .await
+                .map_err(RawstErr::from_http)?;
+
+            if !response.status().is_success() {
+                return Err(status_error(response));
+            }
+            task.check_segment(source, response.headers())?;
+
+            match in_place {
+                Some(partial_path) => {
+                    write_in_place(i, task, response, progressbar, partial_path, self.rate_limiter.as_deref(), config.buffer_size).await?
+                }
+                None => create_cache(i, task, response, progressbar, config.segment_dir(), self.rate_limiter.as_deref(), config.buffer_size).await?,
+            }
+        }
 
         Ok(())
     }
 
-    pub async fn cache_headers(&self, iri: &IriString, additional_headers: &HashMap<String, String>) -> Result<HeaderMap, RawstErr> {
+    // GET of bytes `start` to `end`, along with the connection it holds until its body is read
+    async fn ranged_get(
+        &self,
+        iri: &IriString,
+        additional_headers: &HashMap<String, String>,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<(Response, Option<OwnedSemaphorePermit>), RawstErr> {
+        let mut headers: HeaderMap = additional_headers.try_into().expect("invalid headers");
+
+        let ranged = start > 0 || end.is_some();
+        if ranged {
+            let range_value = match end {
+                Some(end) => format!("bytes={}-{}", start, end),
+                None => format!("bytes={}-", start),
+            };
+
+            headers.insert(RANGE, HeaderValue::from_str(range_value.as_str()).unwrap());
+        }
+
+        let permit = self.connection_permit(iri).await;
+        let response = self
+            .client
+            .get(to_reqwest_url(iri))
+            .headers(headers)
+            .send()
+            .await
+            .map_err(RawstErr::from_http)?;
+
+        if !response.status().is_success() {
+            return Err(status_error(response));
+        }
+        if ranged && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(RawstErr::RangeIgnored(iri.to_string()));
+        }
+
+        Ok((response, permit))
+    }
+
+    /// Fetches the headers of `iri` along with the URL it finally redirected to
+    pub async fn cache_headers(&self, iri: &IriString, additional_headers: &HashMap<String, String>) -> Result<(HeaderMap, IriString), RawstErr> {
 
         let headermap: HeaderMap = (additional_headers).try_into().expect("invalid headers");
 
+        let permit = self.connection_permit(iri).await;
+        let response = self
+            .client
+            .head(to_reqwest_url(iri))
+            .headers(headermap)
+            .send()
+            .await
+            .map_err(preflight_error)?;
+        log::debug!("{} answered over {:?}", iri, response.version());
+
+        match response.status() {
+            StatusCode::OK => Ok((response.headers().to_owned(), final_iri(iri, &response))),
+            StatusCode::METHOD_NOT_ALLOWED => {
+                drop(permit);
+                self.probe_headers(iri, additional_headers).await
+            }
+
+            _ => Err(status_error(response)),
+        }
+    }
+
+    /// Whether `iri` changed since the version with these validators was downloaded
+    ///
+    /// Without validators there's nothing to compare, so it counts as changed.
+    pub async fn is_modified(
+        &self,
+        iri: &IriString,
+        additional_headers: &HashMap<String, String>,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> Result<bool, RawstErr> {
+        let mut headermap: HeaderMap = (additional_headers).try_into().expect("invalid headers");
+
+        let validators = [(IF_NONE_MATCH, etag), (IF_MODIFIED_SINCE, last_modified)];
+        for (name, value) in validators {
+            if let Some(value) = value {
+                let value = HeaderValue::from_str(value).map_err(|_| RawstErr::InvalidHeader(format!("{}: {}", name, value)))?;
+                headermap.insert(name, value);
+            }
+        }
+        if headermap.get(IF_NONE_MATCH).is_none() && headermap.get(IF_MODIFIED_SINCE).is_none() {
+            return Ok(true);
+        }
+
+        let _permit = self.connection_permit(iri).await;
         let response = self
             .client
             .head(to_reqwest_url(iri))
             .headers(headermap)
             .send()
             .await
-            .map_err(|_| RawstErr::Unreachable)?;
+            .map_err(preflight_error)?;
 
         match response.status() {
-            StatusCode::OK => Ok(response.headers().to_owned()),
+            StatusCode::NOT_MODIFIED => Ok(false),
+            status if status.is_success() => Ok(true),
+            // Can't tell without HEAD, downloading again is the safe bet
+            StatusCode::METHOD_NOT_ALLOWED => Ok(true),
+
+            _ => Err(status_error(response)),
+        }
+    }
+
+    // For servers rejecting HEAD, asks for the first byte and rebuilds the headers HEAD would return
+    async fn probe_headers(&self, iri: &IriString, additional_headers: &HashMap<String, String>) -> Result<(HeaderMap, IriString), RawstErr> {
+        log::debug!("HEAD not allowed for {iri}, probing with a ranged GET");
+        let mut headermap: HeaderMap = (additional_headers).try_into().expect("invalid headers");
+        headermap.insert(RANGE, HeaderValue::from_static("bytes=0-0"));
+
+        let _permit = self.connection_permit(iri).await;
+        let response = self
+            .client
+            .get(to_reqwest_url(iri))
+            .headers(headermap)
+            .send()
+            .await
+            .map_err(preflight_error)?;
+        let final_iri = final_iri(iri, &response);
+
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let mut headers = response.headers().to_owned();
+
+                // "bytes 0-0/1234" carries the full size
+                let total = headers
+                    .get(CONTENT_RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.rsplit_once('/'))
+                    .and_then(|(_, total)| total.parse::<u64>().ok());
+
+                match total {
+                    Some(total) => headers.insert(CONTENT_LENGTH, HeaderValue::from(total)),
+                    None => headers.remove(CONTENT_LENGTH),
+                };
+                headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+                Ok((headers, final_iri))
+            }
+            // The range was ignored, so the length is right but ranges can't be used
+            StatusCode::OK => {
+                let mut headers = response.headers().to_owned();
+                headers.insert(ACCEPT_RANGES, HeaderValue::from_static("none"));
+
+                Ok((headers, final_iri))
+            }
+
+            _ => Err(status_error(response)),
+        }
+    }
+}
+
+// Digest files are a line per file, anything larger isn't one
+const MAX_SIDECAR_SIZE: u64 = 64 * 1024;
+
+/// Checksum published next to the file, as `{url}.sha256`
+///
+/// Both `sha256sum` and BSD style lines are understood. A file listing several
+/// downloads is searched for the one named like the URL.
+pub struct SidecarChecksum<'a>(pub &'a HttpHandler);
+
+impl ChecksumSource for SidecarChecksum<'_> {
+    fn name(&self) -> &'static str {
+        "the .sha256 file"
+    }
+
+    fn checksum<'a>(&'a self, task: &'a HttpTask) -> BoxFuture<'a, Result<Option<Checksum>, RawstErr>> {
+        Box::pin(async move {
+            if !matches!(Scheme::of(&task.iri), Ok(Scheme::Http)) {
+                return Ok(None);
+            }
+
+            let mut url = to_reqwest_url(&task.iri);
+            url.set_path(&format!("{}.sha256", url.path()));
+            let headers: HeaderMap = (&task.additional_headers).try_into().expect("invalid headers");
+
+            // The checksum is optional, failing to fetch it doesn't fail the download
+            let _permit = self.0.connection_permit(&task.iri).await;
+            let response = match self.0.client.get(url.clone()).headers(headers).send().await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::warn!("Couldn't fetch {url}, downloading without its checksum: {err}");
+                    return Ok(None);
+                }
+            };
+
+            match response.status() {
+                StatusCode::NOT_FOUND => {
+                    log::debug!("No checksum at {url}");
+                    return Ok(None);
+                }
+                status if !status.is_success() => {
+                    log::warn!("Couldn't fetch {url} ({status}), downloading without its checksum");
+                    return Ok(None);
+                }
+                _ => {}
+            }
+
+            let body = match read_sidecar(response).await {
+                Ok(Some(body)) => body,
+                Ok(None) => {
+                    log::debug!("{url} is too large to be a digest file");
+                    return Ok(None);
+                }
+                Err(err) => {
+                    log::warn!("Couldn't read {url}, downloading without its checksum: {err}");
+                    return Ok(None);
+                }
+            };
+            let name = extract_filename_from_url(&task.iri);
+
+            Ok(parse_sidecar(&body, &name.to_string_lossy()).map(Checksum::Sha256))
+        })
+    }
+}
+
+// Body of a `.sha256` file, None if it's over `MAX_SIDECAR_SIZE`
+//
+// Read a chunk at a time, a body without a Content-Length is only read up to the limit.
+async fn read_sidecar(mut response: Response) -> Result<Option<String>, reqwest::Error> {
+    if response.content_length().is_some_and(|length| length > MAX_SIDECAR_SIZE) {
+        return Ok(None);
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+
+        if body.len() as u64 > MAX_SIDECAR_SIZE {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+// The digest of `name` in a `.sha256` file, or the only one it has
+fn parse_sidecar(body: &str, name: &str) -> Option<String> {
+    let digests: Vec<(&str, Option<&str>)> = body
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+
+            // BSD style, `SHA256 (file.iso) = 9f86d081...`
+            if let Some((file, digest)) = line.strip_prefix("SHA256 (").and_then(|rest| rest.split_once(") = ")) {
+                return Some((digest.trim(), Some(file)));
+            }
+
+            // sha256sum style, `9f86d081...  file.iso`, with a `*` before the name in binary mode
+            let mut fields = line.split_whitespace();
+            let digest = fields.next()?;
+            Some((digest, fields.next().map(|file| file.trim_start_matches('*'))))
+        })
+        .filter(|(digest, _)| digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+        .collect();
+
+    match digests.as_slice() {
+        [(digest, _)] => Some(digest.to_string()),
+        _ => digests.iter().find(|(_, file)| *file == Some(name)).map(|(digest, _)| digest.to_string()),
+    }
+}
+
+/// Checksum the server sent along with the preflight, in `Repr-Digest`, `Digest` or `Content-MD5`
+///
+/// Preferring SHA-256, then SHA-1, then MD5. Digests of a compressed response
+/// aren't of the bytes saved when decompressing, responses with a
+/// `Content-Encoding` are skipped.
+pub struct HeaderChecksum;
+
+impl ChecksumSource for HeaderChecksum {
+    fn name(&self) -> &'static str {
+        "the response headers"
+    }
+
+    fn checksum<'a>(&'a self, task: &'a HttpTask) -> BoxFuture<'a, Result<Option<Checksum>, RawstErr>> {
+        Box::pin(async move {
+            if task.response_header("content-encoding").is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity")) {
+                return Ok(None);
+            }
+
+            // RFC 9530 `sha-256=:base64:`, and RFC 3230 `SHA-256=base64` before it
+            let mut digests: Vec<(String, &str)> = Vec::new();
+            for header in ["repr-digest", "digest"] {
+                if let Some(value) = task.response_header(header) {
+                    for entry in value.split(',') {
+                        if let Some((algorithm, digest)) = entry.split_once('=') {
+                            digests.push((algorithm.trim().to_ascii_lowercase(), digest.trim().trim_matches(':')));
+                        }
+                    }
+                }
+            }
+            // Of the body, a probed range isn't the whole file
+            if task.response_header("content-range").is_none() {
+                if let Some(digest) = task.response_header("content-md5") {
+                    digests.push(("md5".to_string(), digest.trim()));
+                }
+            }
+
+            let find = |names: &[&str]| {
+                digests
+                    .iter()
+                    .find(|(algorithm, _)| names.contains(&algorithm.as_str()))
+                    .and_then(|(_, digest)| BASE64_STANDARD.decode(digest).ok())
+                    .map(|bytes| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+            };
+
+            Ok(find(&["sha-256"]).map(Checksum::Sha256)
+                .or_else(|| find(&["sha", "sha-1"]).map(Checksum::Sha1))
+                .or_else(|| find(&["md5"]).map(Checksum::Md5)))
+        })
+    }
+}
+
+impl DownloadBackend for HttpHandler {
+    async fn fetch_meta(
+        &self,
+        iri: &IriString,
+        additional_headers: &HashMap<String, String>,
+    ) -> Result<(HeaderMap, IriString), RawstErr> {
+        self.cache_headers(iri, additional_headers).await
+    }
+
+    async fn segment(
+        &self,
+        iri: &IriString,
+        additional_headers: &HashMap<String, String>,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Segment, RawstErr> {
+        let (response, permit) = self.ranged_get(iri, additional_headers, start, end).await?;
+
+        Ok(body_stream(response, permit))
+    }
+
+    async fn download(
+        &self,
+        task: &HttpTask,
+        progress: &dyn DownloadProgress,
+        config: &Config,
+    ) -> Result<(), RawstErr> {
+        match config.threads {
+            1 => self.sequential_download(task, progress, config, &Output::Path(config.download_dir.clone())).await,
+            // Only split without a size when the task was made for it
+            _ if task.known_length().is_none() => self.dynamic_download(task, progress, config).await,
+            _ => self.concurrent_download(task, progress, config).await,
+        }
+    }
+}
+
+// The body of `response`, the connection stays taken until it's read
+fn body_stream(response: Response, permit: Option<OwnedSemaphorePermit>) -> Segment {
+    response
+        .bytes_stream()
+        .map(move |chunk| {
+            let _permit = &permit;
+            chunk.map_err(RawstErr::from_http)
+        })
+        .boxed()
+}
+
+fn preflight_error(err: reqwest::Error) -> RawstErr {
+    if err.is_redirect() {
+        RawstErr::TooManyRedirects
+    } else {
+        RawstErr::Unreachable
+    }
+}
+
+// The last hop of the redirect chain, used to name the file
+fn final_iri(requested: &IriString, response: &Response) -> IriString {
+    IriString::try_from(response.url().as_str()).unwrap_or_else(|_| requested.clone())
+}
+
+fn build_proxy(proxy_url: &ProxyUrl) -> Result<Proxy, RawstErr> {
+    let scheme = proxy_url.0.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+
+    match scheme.as_deref() {
+        Some("http" | "https" | "socks5" | "socks5h") => (),
+        _ => return Err(RawstErr::ConfigParse(format!("Unsupported proxy '{}', use http://, https:// or socks5://", proxy_url.redacted()))),
+    }
+
+    // Credentials in the URL are sent as basic auth by reqwest
+    let proxy = Proxy::all(proxy_url.0.as_str())
+        .map_err(|err| RawstErr::ConfigParse(format!("Invalid proxy '{}': {}", proxy_url.redacted(), err)))?;
+
+    Ok(proxy.no_proxy(NoProxy::from_env()))
+}
+
+// Certificates of a PEM file, which may hold a whole chain
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>, RawstErr> {
+    let pem = std::fs::read(path).map_err(|err| RawstErr::ConfigParse(format!("Couldn't read the CA certificate {:?}: {}", path, err)))?;
+
+    match Certificate::from_pem_bundle(&pem) {
+        Ok(certificates) if !certificates.is_empty() => Ok(certificates),
+        Ok(_) => Err(RawstErr::ConfigParse(format!("No certificates found in {:?}", path))),
+        Err(err) => Err(RawstErr::ConfigParse(format!("Invalid CA certificate {:?}: {}", path, err))),
+    }
+}
+
+// Smallest segment worth its own connection
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+// Segments of a dynamic split stop doubling at this size
+const MAX_DYNAMIC_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where the dynamic split of a download without a known size stands
+///
+/// Segments are laid out from the start of the file, the first one of
+/// [`MIN_SEGMENT_SIZE`] and each next one twice as large, up to
+/// [`MAX_DYNAMIC_SEGMENT_SIZE`]. They're requested until one of them finds the
+/// end of the file, by coming back short or being refused with 416 for
+/// starting past it. The ones already running by then are left to finish.
+#[derive(Debug)]
+struct DynamicSplit {
+    // First byte past the end of the file, u64::MAX until a segment finds it
+    end: AtomicU64,
+    // Segments requested so far, the ones with a chunk file to clean up
+    started: AtomicUsize,
+}
+
+impl Default for DynamicSplit {
+    fn default() -> Self {
+        DynamicSplit {
+            end: AtomicU64::new(u64::MAX),
+            started: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl DynamicSplit {
+    /// Offset and length of the nth segment
+    fn segment(n: usize) -> (u64, u64) {
+        let mut start = 0;
+        let mut length = MIN_SEGMENT_SIZE;
+
+        for _ in 0..n {
+            start += length;
+            length = (length * 2).min(MAX_DYNAMIC_SEGMENT_SIZE);
+        }
+
+        (start, length)
+    }
+
+    fn end(&self) -> u64 {
+        self.end.load(Ordering::SeqCst)
+    }
+
+    fn found_end(&self, end: u64) {
+        self.end.fetch_min(end, Ordering::SeqCst);
+    }
+}
+
+/// Number of segments to actually split a download into
+///
+/// Falls back to a single segment when the size is unknown or the server
+/// doesn't accept ranges, and never makes segments smaller than 1MB.
+pub fn effective_threads(content_length: Option<u64>, configured: usize, accept_ranges: bool) -> usize {
+    match content_length {
+        Some(length) if accept_ranges => {
+            let max_segments = usize::try_from(length / MIN_SEGMENT_SIZE).unwrap_or(usize::MAX);
+
+            configured.min(max_segments).max(1)
+        }
+        _ => 1,
+    }
+}
+
+/// Error for an unsuccessful response, returned before any of its body is saved
+fn status_error(response: Response) -> RawstErr {
+    // Only meaningful when the server is overloaded or rate limiting
+    let retry_after = match response.status() {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now())),
+        _ => None,
+    };
+
+    RawstErr::HttpStatus {
+        code: response.status().as_u16(),
+        url: response.url().to_string(),
+        retry_after,
+    }
+}
+
+/// Parses `Retry-After`, either delay seconds or an HTTP date
+///
+/// Dates in the past mean retrying right away.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
 
-            StatusCode::BAD_REQUEST => Err(RawstErr::BadRequest),
-            StatusCode::UNAUTHORIZED => Err(RawstErr::Unauthorized),
-            StatusCode::FORBIDDEN => Err(RawstErr::Forbidden),
-            StatusCode::NOT_FOUND => Err(RawstErr::NotFound),
-            StatusCode::INTERNAL_SERVER_ERROR => Err(RawstErr::InternalServerError),
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+fn merge_span(task: &HttpTask, segments: usize) -> tracing::Span {
+    tracing::debug_span!("merge_files", file = %task.filename.display(), segments)
+}
+
+/// Runs `attempt` until it succeeds, fails with a non transient error or runs out of attempts
+///
+/// `attempt` gets the 1-based attempt number. Waits as long as the server asked
+/// with `Retry-After`, up to [`MAX_RETRY_AFTER`], and otherwise with exponential
+/// backoff as configured by `config.retry`.
+async fn with_retries<F, Fut>(config: &Config, what: &str, mut attempt: F) -> Result<(), RawstErr>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<(), RawstErr>>,
+{
+    let policy = &config.retry;
+    let mut attempt_number = 1;
 
-            _ => Err(RawstErr::Unknown(
-                response.error_for_status().err().unwrap(),
-            )),
+    loop {
+        match attempt(attempt_number).await {
+            Err(err) if err.is_transient() && attempt_number < policy.max_attempts => {
+                let delay = retry_delay(policy, &err, attempt_number);
+                log::warn!(
+                    "{what} failed (attempt {attempt_number}/{}): {err}, retrying in {delay:?}",
+                    policy.max_attempts
+                );
+
+                tokio::time::sleep(delay).await;
+                attempt_number += 1;
+            }
+            result => return result,
         }
     }
 }
 
+/// How long to wait after `err` failed the given attempt, see [`with_retries`]
+fn retry_delay(policy: &RetryPolicy, err: &RawstErr, attempt: u32) -> Duration {
+    match err.retry_after() {
+        Some(retry_after) => retry_after.min(MAX_RETRY_AFTER),
+        None => policy.delay(attempt),
+    }
+}
+
 /// Converts a IriString into reqwest::Url (url::Url)
 fn to_reqwest_url(iri: &IriString) -> reqwest::Url {
     let uri: iri_string::types::UriString = iri.clone().encode_into_uri();
 
     reqwest::Url::parse(uri.as_str()).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::core::config::Jitter;
+    use crate::core::progress::NoProgress;
+    use crate::core::testing::{config_in, MockResponse, MockServer, TempDir};
+
+    #[test]
+    fn tiny_files_are_downloaded_in_one_segment() {
+        assert_eq!(effective_threads(Some(0), 8, true), 1);
+        assert_eq!(effective_threads(Some(1000), 8, true), 1);
+        assert_eq!(effective_threads(Some(MIN_SEGMENT_SIZE - 1), 8, true), 1);
+        assert_eq!(effective_threads(Some(3 * MIN_SEGMENT_SIZE), 8, true), 3);
+    }
+
+    #[test]
+    fn huge_files_use_the_configured_threads() {
+        assert_eq!(effective_threads(Some(100 * 1024 * MIN_SEGMENT_SIZE), 8, true), 8);
+        assert_eq!(effective_threads(Some(u64::MAX), 4, true), 4);
+    }
+
+    #[test]
+    fn servers_without_ranges_or_length_get_a_single_stream() {
+        assert_eq!(effective_threads(Some(100 * MIN_SEGMENT_SIZE), 8, false), 1);
+        assert_eq!(effective_threads(None, 8, true), 1);
+        assert_eq!(effective_threads(None, 8, false), 1);
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT").unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn retry_after_accepts_delay_seconds() {
+        assert_eq!(parse_retry_after("120", now()), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 ", now()), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_accepts_http_dates() {
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now()), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 08:28:00 +0100", now()), Some(Duration::ZERO));
+        // Already past, retry right away
+        assert_eq!(parse_retry_after("Tue, 20 Oct 2015 07:28:00 GMT", now()), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_ignores_garbage() {
+        assert_eq!(parse_retry_after("", now()), None);
+        assert_eq!(parse_retry_after("soon", now()), None);
+        assert_eq!(parse_retry_after("-5", now()), None);
+        assert_eq!(parse_retry_after("1.5", now()), None);
+        assert_eq!(parse_retry_after("Someday, 32 Oct 2015 07:28:00 GMT", now()), None);
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        let policy = RetryPolicy {
+            jitter: Jitter::None,
+            ..RetryPolicy::default()
+        };
+        let unavailable = |retry_after| RawstErr::HttpStatus {
+            code: 503,
+            url: "http://example.com/file".to_string(),
+            retry_after,
+        };
+
+        let asked = parse_retry_after("86400", now());
+        assert_eq!(retry_delay(&policy, &unavailable(asked), 1), MAX_RETRY_AFTER);
+        assert_eq!(retry_delay(&policy, &unavailable(Some(Duration::from_secs(10))), 1), Duration::from_secs(10));
+        // Without Retry-After it's back to the backoff
+        assert_eq!(retry_delay(&policy, &unavailable(None), 2), policy.delay(2));
+    }
+
+    // Retries right away, tests shouldn't wait for the backoff
+    fn quick_retries(dir: &Path) -> Config {
+        let mut config = config_in(dir);
+        config.retry = RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 1,
+            jitter: Jitter::None,
+        };
+
+        config
+    }
+
+    fn task_of(iri: IriString, filename: &str) -> HttpTask {
+        let mut task = HttpTask::new(iri, PathBuf::from(filename), HeaderMap::new(), HashMap::new());
+        task.calculate_chunks(1);
+
+        task
+    }
+
+    #[tokio::test]
+    async fn not_found_fails_without_retrying() {
+        let dir = TempDir::new();
+        let config = quick_retries(dir.path());
+        let server = MockServer::start(|_| MockResponse::new(404, "gone")).await;
+        let handler = HttpHandler::new(&config).unwrap();
+        let task = task_of(server.url("/missing.iso"), "missing.iso");
+
+        let result = handler.sequential_download(&task, &NoProgress, &config, &Output::Path(config.download_dir.clone())).await;
+
+        match result {
+            Err(err @ RawstErr::HttpStatus { code: 404, retry_after: None, .. }) => assert!(!err.is_transient()),
+            other => panic!("expected a 404, got {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 1);
+        assert!(!config.download_dir.join("missing.iso").exists());
+    }
+
+    #[tokio::test]
+    async fn unavailable_is_transient_and_says_when_to_retry() {
+        let dir = TempDir::new();
+        let config = quick_retries(dir.path());
+        let server = MockServer::start(|_| MockResponse::new(503, "").header("Retry-After", "7")).await;
+        let handler = HttpHandler::new(&config).unwrap();
+
+        match handler.cache_headers(&server.url("/busy.iso"), &HashMap::new()).await {
+            Err(err @ RawstErr::HttpStatus { code: 503, .. }) => {
+                assert!(err.is_transient());
+                assert_eq!(err.retry_after(), Some(Duration::from_secs(7)));
+            }
+            other => panic!("expected a 503, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn unavailable_is_retried_until_it_succeeds() {
+        let dir = TempDir::new();
+        let config = quick_retries(dir.path());
+        let answered = Arc::new(AtomicUsize::new(0));
+        let server = MockServer::start({
+            let answered = answered.clone();
+            move |_| match answered.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => MockResponse::new(503, "").header("Retry-After", "0"),
+                _ => MockResponse::new(200, "finally"),
+            }
+        })
+        .await;
+        let handler = HttpHandler::new(&config).unwrap();
+        let task = task_of(server.url("/busy.txt"), "busy.txt");
+
+        handler.sequential_download(&task, &NoProgress, &config, &Output::Path(config.download_dir.clone())).await.unwrap();
+
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(std::fs::read_to_string(config.download_dir.join("busy.txt")).unwrap(), "finally");
+    }
+
+    #[tokio::test]
+    async fn requests_carry_the_configured_user_agent() {
+        let dir = TempDir::new();
+        let mut config = quick_retries(dir.path());
+        config.user_agent = Some("release-mirror-bot/2.1".to_string());
+        let server = MockServer::start(|_| MockResponse::new(200, "hello")).await;
+        let handler = HttpHandler::new(&config).unwrap();
+        let task = task_of(server.url("/hello.txt"), "hello.txt");
+
+        handler.cache_headers(&task.iri, &HashMap::new()).await.unwrap();
+        handler.sequential_download(&task, &NoProgress, &config, &Output::Path(config.download_dir.clone())).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            assert_eq!(request.header("user-agent"), Some("release-mirror-bot/2.1"), "{} {}", request.method, request.path);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_default_to_the_rawst_user_agent() {
+        let dir = TempDir::new();
+        let config = quick_retries(dir.path());
+        let server = MockServer::start(|_| MockResponse::new(200, "hello")).await;
+        let handler = HttpHandler::new(&config).unwrap();
+
+        handler.cache_headers(&server.url("/hello.txt"), &HashMap::new()).await.unwrap();
+
+        assert_eq!(server.requests()[0].header("user-agent"), Some(DEFAULT_USER_AGENT));
+    }
+
+    // Each byte comes well within the read timeout, the whole body takes far longer than the request timeout
+    fn slow_server_config(dir: &Path) -> Config {
+        let mut config = quick_retries(dir);
+        config.read_timeout_ms = 2_000;
+        config.request_timeout_ms = Some(300);
+
+        config
+    }
+
+    fn trickling(body: &str) -> MockResponse {
+        MockResponse::new(200, body).trickle(1, Duration::from_millis(40))
+    }
+
+    #[tokio::test]
+    async fn slow_responses_are_aborted_by_the_request_timeout() {
+        let dir = TempDir::new();
+        let config = slow_server_config(dir.path());
+        let server = MockServer::start(|_| trickling(&"x".repeat(100))).await;
+        let handler = HttpHandler::new(&config).unwrap();
+        let task = task_of(server.url("/slow.bin"), "slow.bin");
+
+        let started = Instant::now();
+        let result = handler.sequential_download(&task, &NoProgress, &config, &Output::Path(config.download_dir.clone())).await;
+
+        assert!(matches!(result, Err(RawstErr::Timeout)), "got {:?}", result);
+        // Every attempt of the policy was made, each cut short
+        assert_eq!(server.requests().len(), config.retry.max_attempts as usize);
+        assert!(started.elapsed() < Duration::from_secs(3), "took {:?}", started.elapsed());
+        assert!(!config.download_dir.join("slow.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn slow_responses_are_retried() {
+        let dir = TempDir::new();
+        let config = slow_server_config(dir.path());
+        let answered = Arc::new(AtomicUsize::new(0));
+        let server = MockServer::start({
+            let answered = answered.clone();
+            move |_| match answered.fetch_add(1, Ordering::SeqCst) {
+                0 => trickling(&"x".repeat(100)),
+                _ => MockResponse::new(200, "quick"),
+            }
+        })
+        .await;
+        let handler = HttpHandler::new(&config).unwrap();
+        let task = task_of(server.url("/slow.txt"), "slow.txt");
+
+        handler.sequential_download(&task, &NoProgress, &config, &Output::Path(config.download_dir.clone())).await.unwrap();
+
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(std::fs::read_to_string(config.download_dir.join("slow.txt")).unwrap(), "quick");
+    }
+
+    #[tokio::test]
+    async fn http2_is_spoken_without_negotiating_it() {
+        let dir = TempDir::new();
+        let mut config = quick_retries(dir.path());
+        config.http_version = HttpVersion::Http2;
+        let server = MockServer::start_h2c(|_| MockResponse::new(200, "over h2")).await;
+        let handler = HttpHandler::new(&config).unwrap();
+        let task = task_of(server.url("/h2.txt"), "h2.txt");
+
+        let response = handler.client.get(to_reqwest_url(&task.iri)).send().await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "over h2");
+
+        handler.sequential_download(&task, &NoProgress, &config, &Output::Path(config.download_dir.clone())).await.unwrap();
+        assert_eq!(std::fs::read_to_string(config.download_dir.join("h2.txt")).unwrap(), "over h2");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    async fn sidecar_of(response: impl Fn() -> MockResponse + Send + Sync + 'static) -> Option<Checksum> {
+        let dir = TempDir::new();
+        let config = quick_retries(dir.path());
+        let server = MockServer::start(move |_| response()).await;
+        let handler = HttpHandler::new(&config).unwrap();
+
+        SidecarChecksum(&handler).checksum(&task_of(server.url("/file.iso"), "file.iso")).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn sidecars_are_read_without_a_length() {
+        let checksum = sidecar_of(|| MockResponse::new(200, format!("{DIGEST}  file.iso\n")).without_length()).await;
+
+        assert_eq!(checksum, Some(Checksum::Sha256(DIGEST.to_string())));
+    }
+
+    #[tokio::test]
+    async fn oversized_sidecars_are_ignored() {
+        let huge = || format!("{DIGEST}  file.iso\n").repeat(MAX_SIDECAR_SIZE as usize / 64);
+
+        assert_eq!(sidecar_of(move || MockResponse::new(200, huge())).await, None);
+        assert_eq!(sidecar_of(move || MockResponse::new(200, huge()).without_length()).await, None);
+    }
+
+    #[tokio::test]
+    async fn failing_to_fetch_a_sidecar_leaves_the_download_unchecked() {
+        assert_eq!(sidecar_of(|| MockResponse::new(404, "not found")).await, None);
+        assert_eq!(sidecar_of(|| MockResponse::new(500, "oops")).await, None);
+
+        // Nothing listens on port 1
+        let dir = TempDir::new();
+        let handler = HttpHandler::new(&config_in(dir.path())).unwrap();
+        let task = task_of("http://127.0.0.1:1/file.iso".parse().unwrap(), "file.iso");
+        assert_eq!(SidecarChecksum(&handler).checksum(&task).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Listens for Ctrl-C for the rest of the program
+///
+/// The first Ctrl-C asks downloads to stop, they flush what they have and
+/// leave a resume manifest behind. The second one exits right away.
+pub fn install_handler() {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                eprintln!("\nAborting");
+                std::process::exit(130);
+            }
+
+            eprintln!("\nInterrupted, saving progress. Press Ctrl-C again to quit immediately");
+        }
+    });
+}
+
+/// Whether Ctrl-C was pressed, downloads should stop as soon as their data is flushed
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
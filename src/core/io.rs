@@ -1,156 +1,536 @@
+use std::fmt;
+use std::io::{self, SeekFrom};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
-
-use futures::{future::join_all, stream::StreamExt};
-use indicatif::ProgressBar;
-use reqwest::Response;
+use std::time::Duration;
+
+use futures::{future::join_all, stream::{Stream, StreamExt}};
+use md5::Md5;
+use reqwest::{Response, StatusCode};
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use tokio::fs::{remove_file, rename, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
 
 use crate::core::config::Config;
 use crate::core::errors::RawstErr;
+use crate::core::interrupt::is_interrupted;
+use crate::core::progress::DownloadProgress;
+use crate::core::rate_limiter::RateLimiter;
 use crate::core::task::{ChunkType, HttpTask};
-use crate::core::utils::chunk_file_name;
+use crate::core::utils::{chunk_file_name, is_chunk_file, partial_file_name};
+
+/// Expected digest of a downloaded file, as lowercase or uppercase hex
+#[derive(Debug, PartialEq, Clone)]
+pub enum Checksum {
+    Sha256(String),
+    Sha1(String),
+    Md5(String),
+}
 
+impl Checksum {
+    pub fn digest(&self) -> &str {
+        match self {
+            Checksum::Sha256(digest) | Checksum::Sha1(digest) | Checksum::Md5(digest) => digest,
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checksum::Sha256(digest) => write!(f, "sha256:{}", digest),
+            Checksum::Sha1(digest) => write!(f, "sha1:{}", digest),
+            Checksum::Md5(digest) => write!(f, "md5:{}", digest),
+        }
+    }
+}
+
+/// Concatenates the cached chunks of `task` into `config.download_dir`
+///
+/// The output is written to a hidden `.partial` file and renamed once complete.
+///
+/// ```no_run
+/// # use std::collections::HashMap;
+/// # use iri_string::types::IriString;
+/// # use reqwest::header::HeaderMap;
+/// use rawst_dl::core::config::Config;
+/// use rawst_dl::core::io::merge_files;
+/// use rawst_dl::core::task::HttpTask;
+///
+/// # async fn run() -> Result<(), rawst_dl::core::errors::RawstErr> {
+/// let config = Config::default();
+/// let iri = IriString::try_from("https://example.com/file.zip").unwrap();
+/// let task = HttpTask::new(iri, "file.zip".into(), HeaderMap::new(), HashMap::new());
+///
+/// merge_files(&task, &config).await?;
+/// # Ok(())
+/// # }
+/// ```
 pub async fn merge_files(task: &HttpTask, config: &Config) -> Result<(), RawstErr> {
     let output_path = config.download_dir.join(&task.filename);
+    let partial_path = config.download_dir.join(partial_file_name(&task.filename, &task.download_id()));
 
-    let output_file = File::create(output_path)
+    let output_file = File::create(&partial_path)
         .await
         .map_err(RawstErr::FileError)?;
 
-    let mut output_file = BufWriter::new(output_file);
+    let mut output_file = BufWriter::with_capacity(config.buffer_size, output_file);
+
+    // Streams each temporary file in order so memory use doesn't grow with the file size
+    for i in 0..config.threads {
+        let chunk_filename = chunk_file_name(task.download_id(), i);
+        assert!(chunk_filename.is_relative());
+        let chunk_path = config.segment_dir().join(chunk_filename);
+
+        let temp_file = File::open(&chunk_path).await.map_err(RawstErr::FileError)?;
+        let mut temp_file = BufReader::with_capacity(config.buffer_size, temp_file);
+
+        tokio::io::copy_buf(&mut temp_file, &mut output_file)
+            .await
+            .map_err(RawstErr::FileError)?;
+
+        remove_file(chunk_path).await.map_err(RawstErr::FileError)?;
+    }
+
+    output_file.flush().await.map_err(RawstErr::FileError)?;
+
+    finalize_file(task, &partial_path, &output_path).await
+}
+
+/// Writes the cached chunks of `task` concurrently into a preallocated output file
+///
+/// Each chunk is copied at its byte offset in `offsets`, so it needs the total size up front.
+pub async fn merge_files_positioned(task: &HttpTask, offsets: &[u64], config: &Config) -> Result<(), RawstErr> {
+    let output_path = config.download_dir.join(&task.filename);
+    let partial_path = config.download_dir.join(partial_file_name(&task.filename, &task.download_id()));
+
+    let output_file = File::create(&partial_path)
+        .await
+        .map_err(RawstErr::FileError)?;
+    output_file
+        .set_len(task.content_length())
+        .await
+        .map_err(RawstErr::FileError)?;
 
     let mut io_tasks = Vec::new();
 
-    // Creates a closure for each temporary file read operation
-    (0..config.threads).for_each(|i| {
-        let chunk_filename = chunk_file_name(task.hashed_file_name(), i);
+    // Creates a task for each temporary file, each with its own handle on the output
+    for (i, offset) in offsets.iter().copied().enumerate() {
+        let chunk_filename = chunk_file_name(task.download_id(), i);
         assert!(chunk_filename.is_relative());
-        let chunk_path = config.cache_dir.join(chunk_filename);
+        let chunk_path = config.segment_dir().join(chunk_filename);
+        let partial_path = partial_path.clone();
+        let buffer_size = config.buffer_size;
 
         let io_task = tokio::spawn(async move {
             let temp_file = File::open(&chunk_path).await.map_err(RawstErr::FileError)?;
-            let mut temp_file = BufReader::new(temp_file);
-            let mut buffer = Vec::new();
+            let mut temp_file = BufReader::with_capacity(buffer_size, temp_file);
+
+            let mut output_file = File::options()
+                .write(true)
+                .open(&partial_path)
+                .await
+                .map_err(RawstErr::FileError)?;
+            output_file
+                .seek(SeekFrom::Start(offset))
+                .await
+                .map_err(RawstErr::FileError)?;
+            let mut output_file = BufWriter::with_capacity(buffer_size, output_file);
 
-            temp_file
-                .read_to_end(&mut buffer)
+            tokio::io::copy_buf(&mut temp_file, &mut output_file)
                 .await
                 .map_err(RawstErr::FileError)?;
+            output_file.flush().await.map_err(RawstErr::FileError)?;
 
             remove_file(chunk_path).await.map_err(RawstErr::FileError)?;
 
-            Ok::<_, RawstErr>(buffer)
+            Ok::<_, RawstErr>(())
         });
 
         io_tasks.push(io_task);
-    });
+    }
 
-    let results = join_all(io_tasks).await;
+    for result in join_all(io_tasks).await {
+        result.map_err(|err| RawstErr::FileError(err.into()))??;
+    }
 
-    for task in results {
-        let data = task.map_err(|err| RawstErr::FileError(err.into()))??;
+    finalize_file(task, &partial_path, &output_path).await
+}
 
-        output_file
-            .write_all(&data)
-            .await
-            .map_err(RawstErr::FileError)?;
-    }
+/// Deletes the cached chunks of `task`, skipping the ones that aren't there
+pub async fn remove_chunks(task: &HttpTask, config: &Config) -> Result<(), RawstErr> {
+    for i in 0..config.threads {
+        let chunk_path = config.segment_dir().join(chunk_file_name(task.download_id(), i));
 
-    output_file.flush().await.map_err(RawstErr::FileError)?;
+        match remove_file(&chunk_path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(RawstErr::FileError(err)),
+            _ => {}
+        }
+    }
 
     Ok(())
 }
 
+/// Moves a complete download to its final path, verifying its size and checksum first
+///
+/// Until then readers never see a half-written file at `output_path`.
+pub async fn finalize_file(task: &HttpTask, partial_path: &Path, output_path: &Path) -> Result<(), RawstErr> {
+    // Catches segments that ended early without an error
+    if let Some(expected) = task.expected_length() {
+        let actual = tokio::fs::metadata(partial_path).await.map_err(RawstErr::FileError)?.len();
+        if actual != expected {
+            log::error!("Removing {partial_path:?}: {actual} bytes instead of {expected}");
+            remove_file(partial_path).await.map_err(RawstErr::FileError)?;
+
+            return Err(RawstErr::SizeMismatch { expected, actual });
+        }
+    }
+
+    if let Some(checksum) = &task.checksum {
+        if let Err(err) = verify_checksum(partial_path, checksum).await {
+            log::error!("Removing {partial_path:?}: {err}");
+            remove_file(partial_path).await.map_err(RawstErr::FileError)?;
+
+            return Err(err);
+        }
+    }
+
+    move_file(partial_path, output_path).await
+}
+
+/// Saves an empty resource without downloading anything
+///
+/// Goes through a partial file like any other download, so a checksum is still verified.
+pub async fn create_empty_file(task: &HttpTask, base_path: &Path) -> Result<(), RawstErr> {
+    let partial_path = partial_download_path(task, base_path);
+    File::create(&partial_path).await.map_err(RawstErr::FileError)?;
+
+    finalize_file(task, &partial_path, &base_path.join(&task.filename)).await
+}
+
+/// Renames `from` to `to`, copying when they are on different filesystems
+pub async fn move_file(from: &Path, to: &Path) -> Result<(), RawstErr> {
+    match rename(from, to).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            tokio::fs::copy(from, to).await.map_err(RawstErr::FileError)?;
+            remove_file(from).await.map_err(RawstErr::FileError)
+        }
+        Err(err) => Err(RawstErr::FileError(err)),
+    }
+}
+
+/// Where a sequential download ends up
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    /// Directory the file is saved in, under the name of the task
+    Path(PathBuf),
+    /// Streamed as it arrives, eg. to pipe it into another program
+    Stdout,
+}
+
 pub async fn create_file(
     task: &HttpTask,
     response: Response,
-    pb: &ProgressBar,
-    base_path: &Path,
+    pb: &dyn DownloadProgress,
+    output: &Output,
+    rate_limiter: Option<&RateLimiter>,
+    buffer_size: usize,
 ) -> Result<(), RawstErr> {
-    let hashed_file_name = chunk_file_name(task.hashed_file_name(), 1);
-    let file_path = base_path.join(hashed_file_name);
+    let base_path = match output {
+        Output::Path(base_path) => base_path,
+        Output::Stdout => {
+            if task.known_length().is_none() {
+                if let Some(length) = response.content_length() {
+                    pb.set_length(length);
+                }
+            }
+
+            let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+            let stream = response.bytes_stream().map(|chunk| chunk.map_err(RawstErr::from_http));
+            return write_stdout(task, stream, resumed, pb, rate_limiter, buffer_size).await;
+        }
+    };
 
-    let mut file = File::options()
-        .append(true)
+    let file_path = partial_download_path(task, base_path);
+
+    // Only a 206 carries the remaining bytes, anything else restarts from scratch
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+    if !resumed && task.total_downloaded.load(Ordering::SeqCst) > 0 {
+        log::warn!("Server ignored the range request, re-downloading {:?} from the start", task.filename);
+        task.total_downloaded.store(0, Ordering::SeqCst);
+        if let ChunkType::Single(chunk) = &task.chunk_data {
+            chunk.downloaded.store(0, Ordering::SeqCst);
+        }
+        pb.set_position(0);
+    }
+
+    // The preflight may not have known the size the download itself announces
+    if task.known_length().is_none() {
+        if let Some(length) = response.content_length() {
+            let already_there = if resumed { task.total_downloaded.load(Ordering::SeqCst) } else { 0 };
+            pb.set_length(already_there + length);
+        }
+    }
+
+    let stream = response.bytes_stream().map(|chunk| chunk.map_err(RawstErr::from_http));
+    save_stream(task, stream, resumed, pb, &file_path, rate_limiter, buffer_size).await?;
+
+    let renamed_file_path = base_path.join(&task.filename);
+    finalize_file(task, &file_path, &renamed_file_path).await
+}
+
+/// Writes the body of a sequential download to `file_path`, after what's there if `resumed`
+///
+/// Shared by every backend, whatever the body comes from.
+pub async fn save_stream<S, B>(
+    task: &HttpTask,
+    mut stream: S,
+    resumed: bool,
+    pb: &dyn DownloadProgress,
+    file_path: &Path,
+    rate_limiter: Option<&RateLimiter>,
+    buffer_size: usize,
+) -> Result<(), RawstErr>
+where
+    S: Stream<Item = Result<B, RawstErr>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let file = File::options()
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
         .create(true)
-        .open(&file_path)
+        .open(file_path)
         .await
         .map_err(RawstErr::FileError)?;
-
-    let mut stream = response.bytes_stream();
+    let mut file = BufWriter::with_capacity(buffer_size, file);
 
     // Recieves bytes as stream and write them into the a file
+    let result = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            let chunk = chunk.as_ref();
+
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.acquire(chunk.len() as u64).await;
+            }
+
+            file.write_all(chunk).await.map_err(RawstErr::FileError)?;
+
+            // Updates total download bytes and the progressbar
+            let chunk_size = chunk.len() as u64;
+            task.total_downloaded
+                .fetch_add(chunk_size, Ordering::SeqCst);
+            pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
+            task.check_size_limit()?;
+
+            if is_interrupted() {
+                return Err(RawstErr::Interrupted);
+            }
+        }
+
+        Ok(())
+    }.await;
+
+    // Counted bytes must reach the disk whatever happened, retries continue after them
+    file.flush().await.map_err(RawstErr::FileError)?;
+
+    result
+}
+
+/// Partial file of a sequential download, renamed once complete
+///
+/// Hidden and named after the output like the in-place ones, so tools watching
+/// the directory see what's coming without mistaking it for the finished file.
+pub fn partial_download_path(task: &HttpTask, base_path: &Path) -> PathBuf {
+    base_path.join(partial_file_name(&task.filename, &task.download_id()))
+}
+
+// Bytes already written can't be taken back, a retry that starts over skips them instead
+pub async fn write_stdout<S, B>(
+    task: &HttpTask,
+    mut stream: S,
+    resumed: bool,
+    pb: &dyn DownloadProgress,
+    rate_limiter: Option<&RateLimiter>,
+    buffer_size: usize,
+) -> Result<(), RawstErr>
+where
+    S: Stream<Item = Result<B, RawstErr>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let mut skip = match resumed {
+        true => 0,
+        false => task.total_downloaded.load(Ordering::SeqCst),
+    };
+
+    // Raw bytes, std's stdout would flush on every newline
+    let mut stdout = BufWriter::with_capacity(buffer_size, tokio::io::stdout());
+
     while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(RawstErr::HttpError)?;
+        let chunk = chunk?;
+        let chunk = chunk.as_ref();
+
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.acquire(chunk.len() as u64).await;
+        }
+
+        let already_written = skip.min(chunk.len() as u64);
+        skip -= already_written;
+        let new_bytes = &chunk[already_written as usize..];
 
-        file.write_all(&chunk).await.map_err(RawstErr::FileError)?;
+        stdout.write_all(new_bytes).await.map_err(RawstErr::FileError)?;
 
-        // Updates total download bytes and the progressbar
-        let chunk_size = chunk.len() as u64;
         task.total_downloaded
-            .fetch_add(chunk_size, Ordering::SeqCst);
+            .fetch_add(new_bytes.len() as u64, Ordering::SeqCst);
         pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
-    }
+        task.check_size_limit()?;
 
-    let renamed_file_path = base_path.join(&task.filename);
-    rename(file_path, renamed_file_path).await.map_err(RawstErr::FileError)?;
+        if is_interrupted() {
+            stdout.flush().await.map_err(RawstErr::FileError)?;
+            return Err(RawstErr::Interrupted);
+        }
+    }
 
-    Ok(())
+    stdout.flush().await.map_err(RawstErr::FileError)
 }
 
 pub async fn create_cache(
     chunk_number: usize,
     task: &HttpTask,
     response: Response,
-    pb: &ProgressBar,
+    pb: &dyn DownloadProgress,
     base_path: &Path,
+    rate_limiter: Option<&RateLimiter>,
+    buffer_size: usize,
 ) -> Result<(), RawstErr> {
-    if let ChunkType::Multiple(chunks) = &task.chunk_data {
+    let chunk_file_name = chunk_file_name(task.download_id(),chunk_number);
+    assert!(chunk_file_name.is_relative());
+    assert!(base_path.is_dir());
 
-        let chunk_file_name = chunk_file_name(task.hashed_file_name(),chunk_number);
-        assert!(chunk_file_name.is_relative());
-        assert!(base_path.is_dir());
+    let filepath = base_path.join(chunk_file_name);
 
-        let filepath = base_path.join(chunk_file_name);
+    let file = File::options()
+        .append(true)
+        .create(true)
+        .open(filepath)
+        .await
+        .map_err(RawstErr::FileError)?;
 
-        let mut file = File::options()
-            .append(true)
-            .create(true)
-            .open(filepath)
-            .await
-            .map_err(RawstErr::FileError)?;
+    write_chunk(chunk_number, task, response, pb, file, rate_limiter, buffer_size).await
+}
+
+/// Whether the segments of `task` are written straight into the output file
+pub fn writes_in_place(task: &HttpTask, config: &Config) -> bool {
+    config.single_file && config.threads > 1 && task.content_length() > 0
+}
+
+/// Creates the output of an in-place download with its final size, keeping what an earlier run wrote
+pub async fn preallocate_output(task: &HttpTask, config: &Config) -> Result<PathBuf, RawstErr> {
+    let partial_path = config.download_dir.join(partial_file_name(&task.filename, &task.download_id()));
+
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&partial_path)
+        .await
+        .map_err(RawstErr::FileError)?;
+    // Sparse where the filesystem allows it, so nothing is written twice
+    file.set_len(task.content_length()).await.map_err(RawstErr::FileError)?;
+
+    Ok(partial_path)
+}
+
+/// Writes a chunk at its offset in the file made by [`preallocate_output`]
+pub async fn write_in_place(
+    chunk_number: usize,
+    task: &HttpTask,
+    response: Response,
+    pb: &dyn DownloadProgress,
+    partial_path: &Path,
+    rate_limiter: Option<&RateLimiter>,
+    buffer_size: usize,
+) -> Result<(), RawstErr> {
+    let ChunkType::Multiple(chunks) = &task.chunk_data else {
+        return Ok(());
+    };
+    let chunk = &chunks[chunk_number];
+
+    let mut file = File::options()
+        .write(true)
+        .open(partial_path)
+        .await
+        .map_err(RawstErr::FileError)?;
+    file.seek(SeekFrom::Start(chunk.start + chunk.downloaded.load(Ordering::SeqCst)))
+        .await
+        .map_err(RawstErr::FileError)?;
+
+    write_chunk(chunk_number, task, response, pb, file, rate_limiter, buffer_size).await
+}
+
+// Appends the body to `file`, counting the bytes in the chunk
+async fn write_chunk(
+    chunk_number: usize,
+    task: &HttpTask,
+    response: Response,
+    pb: &dyn DownloadProgress,
+    file: File,
+    rate_limiter: Option<&RateLimiter>,
+    buffer_size: usize,
+) -> Result<(), RawstErr> {
+    if let ChunkType::Multiple(chunks) = &task.chunk_data {
+        let mut file = BufWriter::with_capacity(buffer_size, file);
 
         let mut stream = response.bytes_stream();
 
         // Recieves bytes as stream and write them into the a file
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(RawstErr::HttpError)?;
+        let result = async {
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(RawstErr::from_http)?;
 
-            file.write_all(&chunk).await.map_err(RawstErr::FileError)?;
+                if let Some(rate_limiter) = rate_limiter {
+                    rate_limiter.acquire(chunk.len() as u64).await;
+                }
 
-            file.flush().await.map_err(RawstErr::FileError)?;
+                file.write_all(&chunk).await.map_err(RawstErr::FileError)?;
 
-            // Updates total download bytes and the progressbar
-            let chunk_size = chunk.len() as u64;
-            task.total_downloaded
-                .fetch_add(chunk_size, Ordering::SeqCst);
-            pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
+                // Updates downloaded bytes for each chunk
+                let chunk_size = chunk.len() as u64;
+                let chunk_downloaded = chunks[chunk_number]
+                    .downloaded
+                    .fetch_add(chunk_size, Ordering::SeqCst) + chunk_size;
+                pb.set_segment(chunk_number, chunk_downloaded, task.chunk_length(&chunks[chunk_number]));
 
-            // Updates downloaded bytes for each chunk
-            chunks[chunk_number]
-                .downloaded
-                .fetch_add(chunk_size, Ordering::SeqCst);
-        }
+                // Updates total download bytes and the progressbar
+                task.total_downloaded
+                    .fetch_add(chunk_size, Ordering::SeqCst);
+                pb.set_position(task.total_downloaded.load(Ordering::SeqCst));
+                task.check_size_limit()?;
+
+                if is_interrupted() {
+                    return Err(RawstErr::Interrupted);
+                }
+            }
+
+            Ok(())
+        }.await;
+
+        // Retries continue after the bytes counted so far, they have to be on disk
+        file.flush().await.map_err(RawstErr::FileError)?;
+
+        result?;
     }
 
     Ok(())
 }
 
 pub fn get_cache_sizes(
-    hashed_filename: String,
+    task: &HttpTask,
     threads: usize,
     config: Config,
 ) -> Result<Vec<u64>, RawstErr> {
@@ -158,23 +538,17 @@ pub fn get_cache_sizes(
 
     match threads > 1 {
         false => {
-            let file_name = chunk_file_name(hashed_filename, 1);
-
-            let path = config.download_dir.join(file_name);
-
-            let meta_data = std::fs::metadata(path).map_err(RawstErr::FileError)?;
+            let path = partial_download_path(task, &config.download_dir);
 
-            cache_sizes.push(meta_data.len());
+            cache_sizes.push(resume_offset(&path)?);
         }
         true => {
             (0..threads).try_for_each(|i| {
-                let chunk_filename = chunk_file_name(hashed_filename.clone(), i);
+                let chunk_filename = chunk_file_name(task.download_id(), i);
 
-                let path = config.cache_dir.join(chunk_filename);
+                let path = config.segment_dir().join(chunk_filename);
 
-                let meta_data = std::fs::metadata(path).map_err(RawstErr::FileError)?;
-
-                cache_sizes.push(meta_data.len());
+                cache_sizes.push(resume_offset(&path)?);
                 Ok::<_, RawstErr>(())
             })?;
         }
@@ -183,6 +557,173 @@ pub fn get_cache_sizes(
     Ok(cache_sizes)
 }
 
+/// Hashes the file at `path` and compares it against `expected`
+pub async fn verify_checksum(path: &Path, expected: &Checksum) -> Result<(), RawstErr> {
+    let file = File::open(path).await.map_err(RawstErr::FileError)?;
+    let mut reader = BufReader::new(file);
+
+    let actual = match expected {
+        Checksum::Sha256(_) => hash_reader::<Sha256>(&mut reader).await?,
+        Checksum::Sha1(_) => hash_reader::<Sha1>(&mut reader).await?,
+        Checksum::Md5(_) => hash_reader::<Md5>(&mut reader).await?,
+    };
+
+    if actual.eq_ignore_ascii_case(expected.digest()) {
+        Ok(())
+    } else {
+        Err(RawstErr::ChecksumMismatch {
+            expected: expected.digest().to_lowercase(),
+            actual,
+        })
+    }
+}
+
+/// Sha256 of the file at `path`
+pub async fn file_checksum(path: &Path) -> Result<Checksum, RawstErr> {
+    let file = File::open(path).await.map_err(RawstErr::FileError)?;
+    let mut reader = BufReader::new(file);
+
+    Ok(Checksum::Sha256(hash_reader::<Sha256>(&mut reader).await?))
+}
+
+// Streams the file through the hasher so large downloads aren't loaded in memory
+async fn hash_reader<D: Digest>(reader: &mut BufReader<File>) -> Result<String, RawstErr> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer).await.map_err(RawstErr::FileError)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: c_path is a valid C string and stat a properly sized out parameter
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    #[allow(clippy::unnecessary_cast)] // The field types differ between platforms
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Bytes available on the filesystem holding `path`
+///
+/// Not implemented on this platform, so the space check always passes.
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Fails with [`RawstErr::InsufficientSpace`] if `dir` can't hold `bytes` plus a 10% margin
+pub fn ensure_space(dir: &Path, bytes: u64) -> Result<(), RawstErr> {
+    let needed = bytes.saturating_add(bytes / 10);
+    let available = available_space(dir).map_err(RawstErr::FileError)?;
+
+    if available < needed {
+        return Err(RawstErr::InsufficientSpace { needed, available });
+    }
+
+    Ok(())
+}
+
+/// Creates `dir` if needed and checks files can be written in it
+///
+/// Permissions alone don't tell (read-only mounts, ACLs), so a probe file is
+/// written and removed.
+pub fn ensure_writable_dir(dir: &Path) -> Result<(), RawstErr> {
+    std::fs::create_dir_all(dir).map_err(RawstErr::FileError)?;
+
+    let probe = dir.join(".rawst-write-test");
+    std::fs::write(&probe, b"").map_err(RawstErr::FileError)?;
+    std::fs::remove_file(&probe).map_err(RawstErr::FileError)
+}
+
+/// Number of bytes already written to a partial file, 0 when it doesn't exist yet
+pub fn resume_offset(path: &Path) -> Result<u64, RawstErr> {
+    match std::fs::metadata(path) {
+        Ok(meta_data) => Ok(meta_data.len()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(RawstErr::FileError(err)),
+    }
+}
+
+/// A chunk file left behind by a crashed or abandoned download
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StaleChunk {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Removes chunk files in `config.cache_dir` and `config.temp_dir` that weren't modified in `older_than`
+///
+/// Chunks of running downloads are written to constantly, so only the ones
+/// left behind by crashed or abandoned downloads are old enough to go. With
+/// `dry_run` the files are only listed. Returns the chunks removed, or that would be.
+pub async fn clean_cache(config: &Config, older_than: Duration, dry_run: bool) -> Result<Vec<StaleChunk>, RawstErr> {
+    let mut stale = clean_chunks(&config.cache_dir, older_than, dry_run).await?;
+
+    // Chunks of downloads started before temp_dir was set stay in cache_dir
+    if config.segment_dir() != config.cache_dir {
+        stale.extend(match clean_chunks(config.segment_dir(), older_than, dry_run).await {
+            Err(RawstErr::FileError(err)) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            result => result?,
+        });
+    }
+
+    Ok(stale)
+}
+
+async fn clean_chunks(dir: &Path, older_than: Duration, dry_run: bool) -> Result<Vec<StaleChunk>, RawstErr> {
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(RawstErr::FileError)?;
+    let mut stale = Vec::new();
+
+    while let Some(entry) = entries.next_entry().await.map_err(RawstErr::FileError)? {
+        let path = entry.path();
+        if !is_chunk_file(&path) {
+            continue;
+        }
+
+        let meta_data = entry.metadata().await.map_err(RawstErr::FileError)?;
+        let age = meta_data
+            .modified()
+            .map_err(RawstErr::FileError)?
+            .elapsed()
+            .unwrap_or_default();
+
+        if !meta_data.is_file() || age < older_than {
+            continue;
+        }
+
+        if !dry_run {
+            log::trace!("Removing stale chunk {:?}", path);
+            remove_file(&path).await.map_err(RawstErr::FileError)?;
+        }
+
+        stale.push(StaleChunk { path, bytes: meta_data.len() });
+    }
+
+    Ok(stale)
+}
+
 pub async fn read_links(filepath: &PathBuf) -> Result<String, RawstErr> {
     let mut file = File::open(filepath).await.map_err(RawstErr::FileError)?;
 
@@ -194,3 +735,207 @@ pub async fn read_links(filepath: &PathBuf) -> Result<String, RawstErr> {
 
     Ok(file_content)
 }
+
+/// Reads a links file piped into stdin
+///
+/// tokio reads stdin on a blocking thread, the runtime keeps going meanwhile.
+pub async fn read_links_from_stdin() -> Result<String, RawstErr> {
+    let mut content = String::new();
+
+    tokio::io::stdin()
+        .read_to_string(&mut content)
+        .await
+        .map_err(RawstErr::FileError)?;
+
+    Ok(content)
+}
+
+/// URLs in the contents of a links file, one per line
+///
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_links(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use iri_string::types::IriString;
+    use reqwest::header::HeaderMap;
+
+    use super::*;
+    use crate::core::progress::NoProgress;
+    use crate::core::testing::{config_in, TempDir};
+
+    fn task(filename: &str) -> HttpTask {
+        HttpTask::new(IriString::try_from("http://example.com/file").unwrap(), PathBuf::from(filename), HeaderMap::new(), HashMap::new())
+    }
+
+    #[tokio::test]
+    async fn save_stream_stops_past_the_size_limit() {
+        let dir = TempDir::new();
+        let mut task = task("file");
+        task.max_size = Some(2500);
+        let chunks: Vec<Result<Vec<u8>, RawstErr>> = (0..5).map(|_| Ok(vec![7u8; 1000])).collect();
+        let path = dir.path().join("file");
+
+        let result = save_stream(&task, futures::stream::iter(chunks), false, &NoProgress, &path, None, 4096).await;
+
+        assert!(matches!(result, Err(RawstErr::SizeLimitExceeded { limit: 2500 })), "got {:?}", result);
+        // Stopped at the first chunk past the limit, everything counted is on disk
+        assert_eq!(task.total_downloaded.load(Ordering::SeqCst), 3000);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3000);
+    }
+
+    #[tokio::test]
+    async fn save_stream_writes_everything_under_the_size_limit() {
+        let dir = TempDir::new();
+        let mut task = task("file");
+        task.max_size = Some(5000);
+        let chunks: Vec<Result<Vec<u8>, RawstErr>> = (0..5).map(|i| Ok(vec![i; 1000])).collect();
+        let path = dir.path().join("file");
+
+        save_stream(&task, futures::stream::iter(chunks), false, &NoProgress, &path, None, 4096).await.unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), 5000);
+        assert!(written.chunks(1000).enumerate().all(|(i, chunk)| chunk.iter().all(|&byte| byte as usize == i)));
+    }
+
+    // Writes `chunks` where the segments of `task` are cached, returning them concatenated
+    fn write_chunks(task: &HttpTask, config: &Config, chunks: &[Vec<u8>]) -> Vec<u8> {
+        for (i, chunk) in chunks.iter().enumerate() {
+            std::fs::write(config.segment_dir().join(chunk_file_name(task.download_id(), i)), chunk).unwrap();
+        }
+
+        chunks.concat()
+    }
+
+    // Megabytes of noise, with odd sizes so chunk boundaries don't line up with the buffer
+    fn large_chunks() -> Vec<Vec<u8>> {
+        [3 * 1024 * 1024 + 17, 2 * 1024 * 1024 + 1, 1024 * 1024 - 5]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| (0..len).map(|byte: usize| (byte.wrapping_mul(31) ^ (i * 101)) as u8).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn merge_files_concatenates_the_chunks_in_order() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.threads = 3;
+        let task = task("large.bin");
+        let expected = write_chunks(&task, &config, &large_chunks());
+
+        merge_files(&task, &config).await.unwrap();
+
+        assert_eq!(std::fs::read(config.download_dir.join("large.bin")).unwrap(), expected);
+        assert!(!partial_download_path(&task, &config.download_dir).exists());
+        for i in 0..3 {
+            assert!(!config.segment_dir().join(chunk_file_name(task.download_id(), i)).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_merge_leaves_nothing_at_the_output() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.threads = 3;
+        let task = task("large.bin");
+        write_chunks(&task, &config, &large_chunks());
+        // The last chunk is gone halfway through the merge
+        std::fs::remove_file(config.segment_dir().join(chunk_file_name(task.download_id(), 2))).unwrap();
+
+        let result = merge_files(&task, &config).await;
+
+        assert!(matches!(result, Err(RawstErr::FileError(_))), "got {:?}", result);
+        assert!(!config.download_dir.join("large.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_positioned_merge_leaves_nothing_at_the_output() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.threads = 3;
+        let task = task("large.bin");
+        let chunks = large_chunks();
+        write_chunks(&task, &config, &chunks);
+        std::fs::remove_file(config.segment_dir().join(chunk_file_name(task.download_id(), 1))).unwrap();
+        let offsets = [0, chunks[0].len() as u64, (chunks[0].len() + chunks[1].len()) as u64];
+
+        let result = merge_files_positioned(&task, &offsets, &config).await;
+
+        assert!(matches!(result, Err(RawstErr::FileError(_))), "got {:?}", result);
+        assert!(!config.download_dir.join("large.bin").exists());
+    }
+
+    // Digests of no bytes at all
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const EMPTY_MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+    #[tokio::test]
+    async fn empty_files_are_created_without_a_checksum() {
+        let dir = TempDir::new();
+        let task = task("empty.txt");
+
+        create_empty_file(&task, dir.path()).await.unwrap();
+
+        assert_eq!(std::fs::metadata(dir.path().join("empty.txt")).unwrap().len(), 0);
+        assert!(!partial_download_path(&task, dir.path()).exists());
+    }
+
+    #[tokio::test]
+    async fn empty_files_are_created_with_a_matching_checksum() {
+        let dir = TempDir::new();
+        for (filename, checksum) in [("a.txt", Checksum::Sha256(EMPTY_SHA256.to_uppercase())), ("b.txt", Checksum::Md5(EMPTY_MD5.to_string()))] {
+            let mut task = task(filename);
+            task.checksum = Some(checksum);
+
+            create_empty_file(&task, dir.path()).await.unwrap();
+
+            assert_eq!(std::fs::metadata(dir.path().join(filename)).unwrap().len(), 0);
+            assert!(!partial_download_path(&task, dir.path()).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_files_with_a_wrong_checksum_leave_nothing_behind() {
+        let dir = TempDir::new();
+        let mut task = task("empty.txt");
+        task.checksum = Some(Checksum::Md5("0".repeat(32)));
+
+        let result = create_empty_file(&task, dir.path()).await;
+
+        assert!(result.is_err());
+        assert!(!dir.path().join("empty.txt").exists());
+        assert!(!partial_download_path(&task, dir.path()).exists());
+    }
+
+    #[tokio::test]
+    async fn clean_cache_lists_stale_chunks_and_removes_them() {
+        let dir = TempDir::new();
+        let config = config_in(dir.path());
+        let chunk = config.cache_dir.join(chunk_file_name("abc".to_string(), 0));
+        std::fs::write(&chunk, [0u8; 100]).unwrap();
+        std::fs::write(config.cache_dir.join("notes.txt"), "not a chunk").unwrap();
+        let expected = vec![StaleChunk { path: chunk.clone(), bytes: 100 }];
+
+        assert_eq!(clean_cache(&config, Duration::ZERO, true).await.unwrap(), expected);
+        assert!(chunk.exists(), "dry runs keep the chunks");
+
+        // Too recent to be stale
+        assert_eq!(clean_cache(&config, Duration::from_secs(3600), false).await.unwrap(), Vec::new());
+        assert!(chunk.exists());
+
+        assert_eq!(clean_cache(&config, Duration::ZERO, false).await.unwrap(), expected);
+        assert!(!chunk.exists());
+        assert!(config.cache_dir.join("notes.txt").exists());
+    }
+}
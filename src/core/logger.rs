@@ -1,10 +1,28 @@
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
 
+use chrono::{Local, NaiveDateTime, TimeDelta};
 use fern::colors::Color;
 use fern::colors::ColoredLevelConfig;
+use fern::FormatCallback;
+use serde::{Deserialize, Serialize};
 
 use crate::cli::args::Arguments;
-use crate::core::config::Config;
+use crate::core::config::{Config, LOG_TIMESTAMP_FORMAT};
+use crate::core::errors::RawstErr;
+use crate::core::output::{status, stdout_is_data};
+
+/// How lines are written to the log file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `[timestamp level target] message`
+    #[default]
+    Text,
+    /// One object per line with `timestamp`, `level`, `target` and `message`, for log aggregators
+    Json,
+}
 
 fn default_colors() -> ColoredLevelConfig {
     ColoredLevelConfig::default()
@@ -23,36 +41,43 @@ fn no_colors() -> ColoredLevelConfig {
 }
 static NO_COLORS: LazyLock<ColoredLevelConfig> = LazyLock::new(no_colors);
 
+// Only report through tracing, whose events now reach the log too, they'd flood it with connection internals
+const TRACING_ONLY_TARGETS: &[&str] = &["h2", "hyper", "hyper_util"];
+
 pub fn init(config: &Config, args: &Arguments) -> Result<(), fern::InitError> {
     let log_file_path = config.log_file_path();
 
-    println!("Initialising logger ({:?})...", log_file_path);
+    status(format!("Initialising logger ({:?})...", log_file_path));
 
     let colors: &ColoredLevelConfig = match args.color.color {
         concolor_clap::ColorChoice::Never => &NO_COLORS,
         _ => &COLORS,
     };
 
-    fern::Dispatch::new()
+    let mut dispatch = fern::Dispatch::new()
+        // Spans of downloads and segments are logged when created, not each time they're polled
+        .level_for("tracing::span::active", log::LevelFilter::Off)
+        // Logs every cookie it stores, values included
+        .level_for("cookie_store", log::LevelFilter::Off);
+    for target in TRACING_ONLY_TARGETS {
+        dispatch = dispatch.level_for(*target, log::LevelFilter::Off);
+    }
+
+    dispatch
         .chain(
             // Log file
             fern::Dispatch::new()
                 .level(args.log_verbosity.unwrap_or(log::LevelFilter::Debug))
-                .format(|out, message, record| {
-                    out.finish(format_args!(
-                        "[{} {} {}] {}",
-                        humantime::format_rfc3339_seconds(std::time::SystemTime::now()),
-                        record.level(),
-                        record.target(),
-                        message
-                    ))
+                .format(match config.log_format {
+                    LogFormat::Text => format_text,
+                    LogFormat::Json => format_json,
                 })
                 .chain(fern::log_file(log_file_path)?),
         )
         .chain(
-            // stdout
+            // Terminal, stdout unless the download is written there
             fern::Dispatch::new()
-                .level(args.verbosity.unwrap_or(log::LevelFilter::Warn))
+                .level(args.terminal_log_level().unwrap_or(log::LevelFilter::Warn))
                 .format(|out, message, record| {
                     out.finish(format_args!(
                         "[{} {} {}] {}",
@@ -62,9 +87,176 @@ pub fn init(config: &Config, args: &Arguments) -> Result<(), fern::InitError> {
                         message
                     ))
                 })
-                .chain(std::io::stdout()),
+                .chain(match stdout_is_data() {
+                    true => fern::Output::from(std::io::stderr()),
+                    false => fern::Output::from(std::io::stdout()),
+                }),
         )
         .apply()?;
 
     Ok(())
 }
+
+fn format_text(out: FormatCallback, message: &std::fmt::Arguments, record: &log::Record) {
+    out.finish(format_args!(
+        "[{} {} {}] {}",
+        humantime::format_rfc3339_seconds(std::time::SystemTime::now()),
+        record.level(),
+        record.target(),
+        message
+    ))
+}
+
+fn format_json(out: FormatCallback, message: &std::fmt::Arguments, record: &log::Record) {
+    let line = serde_json::json!({
+        "timestamp": humantime::format_rfc3339_seconds(std::time::SystemTime::now()).to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message.to_string(),
+    });
+
+    out.finish(format_args!("{}", line))
+}
+
+/// Deletes logs of past runs beyond `log_retention_days` and `max_log_files`
+///
+/// Only files named like [`Config::log_file_path`] names them are touched,
+/// anything else in `log_dir` is left alone. Returns the number of logs deleted.
+pub fn prune_logs(config: &Config) -> Result<usize, RawstErr> {
+    if config.log_retention_days.is_none() && config.max_log_files.is_none() {
+        return Ok(0);
+    }
+
+    let entries = match std::fs::read_dir(&config.log_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(RawstErr::FileError(err)),
+    };
+
+    let mut logs: Vec<(NaiveDateTime, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            Some((log_start_time(&path)?, path))
+        })
+        .collect();
+    // Newest first, so the ones past `max_log_files` are the oldest
+    logs.sort_by_key(|(started, _)| std::cmp::Reverse(*started));
+
+    let cutoff = config
+        .log_retention_days
+        .and_then(|days| TimeDelta::try_days(days as i64))
+        .map(|retention| Local::now().naive_local() - retention);
+
+    let mut deleted = 0;
+    for (i, (started, path)) in logs.iter().enumerate() {
+        let too_many = config.max_log_files.is_some_and(|max| i >= max);
+        let too_old = cutoff.is_some_and(|cutoff| *started < cutoff);
+
+        if too_many || too_old {
+            std::fs::remove_file(path).map_err(RawstErr::FileError)?;
+            log::debug!("Deleted old log {:?}", path);
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+// When the run that wrote `path` started, `None` if it isn't named like a rawst log
+fn log_start_time(path: &Path) -> Option<NaiveDateTime> {
+    if path.extension()? != "log" {
+        return None;
+    }
+
+    // 2024-12-31_23h-59m-59s-1
+    let (timestamp, thread_id) = path.file_stem()?.to_str()?.rsplit_once('-')?;
+    if thread_id.is_empty() || !thread_id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    NaiveDateTime::parse_from_str(timestamp, LOG_TIMESTAMP_FORMAT).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::core::testing::{config_in, TempDir};
+
+    // Writes a log named like a run started `days_ago`, returning its file name
+    fn write_log(config: &Config, days_ago: i64, thread_id: u32) -> String {
+        let started = Local::now().naive_local() - TimeDelta::try_days(days_ago).unwrap();
+        let name = format!("{}-{}.log", started.format(LOG_TIMESTAMP_FORMAT), thread_id);
+        std::fs::write(config.log_dir.join(&name), "").unwrap();
+
+        name
+    }
+
+    fn log_dir_files(config: &Config) -> BTreeSet<String> {
+        std::fs::read_dir(&config.log_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn logs_are_kept_without_limits() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.log_retention_days = None;
+        config.max_log_files = None;
+        let before: BTreeSet<String> = (0..5).map(|days| write_log(&config, days * 100, 1)).collect();
+
+        assert_eq!(prune_logs(&config).unwrap(), 0);
+        assert_eq!(log_dir_files(&config), before);
+    }
+
+    #[test]
+    fn logs_past_the_retention_are_deleted() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.log_retention_days = Some(7);
+        config.max_log_files = None;
+        let recent = [write_log(&config, 0, 1), write_log(&config, 6, 2)];
+        write_log(&config, 8, 3);
+        write_log(&config, 365, 4);
+
+        assert_eq!(prune_logs(&config).unwrap(), 2);
+        assert_eq!(log_dir_files(&config), BTreeSet::from(recent));
+    }
+
+    #[test]
+    fn only_the_newest_logs_are_kept_past_max_log_files() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.log_retention_days = None;
+        config.max_log_files = Some(2);
+        let newest = [write_log(&config, 1, 1), write_log(&config, 2, 2)];
+        write_log(&config, 3, 3);
+        write_log(&config, 4, 4);
+
+        assert_eq!(prune_logs(&config).unwrap(), 2);
+        assert_eq!(log_dir_files(&config), BTreeSet::from(newest));
+    }
+
+    #[test]
+    fn other_files_in_the_log_dir_are_left_alone() {
+        let dir = TempDir::new();
+        let mut config = config_in(dir.path());
+        config.log_retention_days = Some(1);
+        config.max_log_files = Some(1);
+        let others = ["notes.txt", "2020-01-01_00h-00m-00s.log", "2020-01-01_00h-00m-00s-x.log", "old-1.log", "2020-01-01_00h-00m-00s-1.log.gz"];
+        for other in others {
+            std::fs::write(config.log_dir.join(other), "").unwrap();
+        }
+        let newest = write_log(&config, 0, 1);
+        write_log(&config, 30, 2);
+
+        assert_eq!(prune_logs(&config).unwrap(), 1);
+        let mut expected: BTreeSet<String> = others.iter().map(|other| other.to_string()).collect();
+        expected.insert(newest);
+        assert_eq!(log_dir_files(&config), expected);
+    }
+}
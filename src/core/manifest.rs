@@ -0,0 +1,153 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+use chrono::{DateTime, Local};
+use iri_string::types::IriString;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::core::config::Config;
+use crate::core::errors::RawstErr;
+use crate::core::io::writes_in_place;
+use crate::core::task::{ChunkType, HttpTask};
+
+/// State of an interrupted download, saved in `cache_dir`
+///
+/// Found again by URL, so running the same download picks up where it stopped.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumeManifest {
+    pub url: IriString,
+    // History record of the download
+    pub id: String,
+    pub filename: PathBuf,
+    pub download_dir: PathBuf,
+    pub threads: usize,
+    pub timestamp: String,
+    pub headers: HashMap<String, String>,
+    pub segments: Vec<Segment>,
+    // Segments were written into the output file, not into chunk files
+    #[serde(default)]
+    pub single_file: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub downloaded: u64,
+}
+
+impl ResumeManifest {
+    pub fn new(task: &HttpTask, config: &Config, id: String) -> Self {
+        ResumeManifest {
+            url: task.iri.clone(),
+            id,
+            filename: task.filename.clone(),
+            download_dir: config.download_dir.clone(),
+            threads: config.threads,
+            timestamp: task.timestamp.to_string(),
+            headers: task.additional_headers.clone(),
+            segments: segments(task),
+            single_file: writes_in_place(task, config),
+        }
+    }
+
+    /// Whether `task` splits the file the same way as the interrupted download did
+    pub fn matches(&self, task: &HttpTask) -> bool {
+        let starts = |segments: &[Segment]| segments.iter().map(|segment| (segment.start, segment.end)).collect::<Vec<_>>();
+
+        starts(&self.segments) == starts(&segments(task))
+    }
+
+    pub fn path(cache_dir: &Path, iri: &IriString) -> PathBuf {
+        let hash = Sha256::digest(iri.as_str().as_bytes());
+
+        cache_dir.join(format!("{:x}.resume.json", hash))
+    }
+
+    pub async fn save(&self, cache_dir: &Path) -> Result<(), RawstErr> {
+        let json_str = serde_json::to_string_pretty(self).unwrap();
+
+        fs::write(Self::path(cache_dir, &self.url), json_str).await.map_err(RawstErr::FileError)
+    }
+
+    /// Manifest left by an interrupted download of `iri`, if any
+    pub async fn load(cache_dir: &Path, iri: &IriString) -> Result<Option<Self>, RawstErr> {
+        let path = Self::path(cache_dir, iri);
+
+        let json_str = match fs::read_to_string(&path).await {
+            Ok(json_str) => json_str,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(RawstErr::FileError(err)),
+        };
+
+        serde_json::from_str(&json_str)
+            .map(Some)
+            .map_err(|err| RawstErr::HistoryParse(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Every manifest in `cache_dir`, the most recently started download first
+    ///
+    /// Manifests that can't be read are skipped, they only cost a fresh start.
+    pub async fn list(cache_dir: &Path) -> Result<Vec<Self>, RawstErr> {
+        let mut entries = match fs::read_dir(cache_dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(RawstErr::FileError(err)),
+        };
+
+        let mut manifests = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(RawstErr::FileError)? {
+            let path = entry.path();
+            if !path.to_string_lossy().ends_with(".resume.json") {
+                continue;
+            }
+
+            let manifest = fs::read_to_string(&path)
+                .await
+                .map_err(|err| err.to_string())
+                .and_then(|json_str| serde_json::from_str::<Self>(&json_str).map_err(|err| err.to_string()));
+
+            match manifest {
+                Ok(manifest) => manifests.push(manifest),
+                Err(err) => log::warn!("Skipping resume manifest {:?}: {}", path, err),
+            }
+        }
+
+        manifests.sort_by_key(|manifest| Reverse(DateTime::<Local>::from_str(&manifest.timestamp).ok()));
+
+        Ok(manifests)
+    }
+
+    pub async fn remove(cache_dir: &Path, iri: &IriString) -> Result<(), RawstErr> {
+        match fs::remove_file(Self::path(cache_dir, iri)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(RawstErr::FileError(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn segments(task: &HttpTask) -> Vec<Segment> {
+    match &task.chunk_data {
+        // Sequential downloads only keep count in the task
+        ChunkType::Single(chunk) => vec![Segment {
+            start: chunk.start,
+            end: chunk.y_offset,
+            downloaded: task.total_downloaded.load(Ordering::SeqCst),
+        }],
+        ChunkType::Multiple(chunks) => chunks
+            .iter()
+            .map(|chunk| Segment {
+                start: chunk.start,
+                end: chunk.y_offset,
+                downloaded: chunk.downloaded.load(Ordering::SeqCst),
+            })
+            .collect(),
+        ChunkType::None => Vec::new(),
+    }
+}
@@ -0,0 +1,254 @@
+use std::path::{Path, PathBuf};
+
+use iri_string::types::IriString;
+use roxmltree::{Document, Node};
+
+use crate::core::errors::RawstErr;
+use crate::core::io::Checksum;
+use crate::core::utils::is_unsafe_filename;
+
+/// A file described by a metalink, with every URL it can be fetched from
+///
+/// Both Metalink 4 (`.meta4`, RFC 5854) and the older 3.0 (`.metalink`)
+/// formats are read.
+#[derive(Debug, PartialEq)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<u64>,
+    /// Most preferred first
+    pub urls: Vec<IriString>,
+    /// The strongest hash listed, if any
+    pub checksum: Option<Checksum>,
+}
+
+impl MetalinkFile {
+    /// Name to save the file as, without the directories the metalink puts it in
+    ///
+    /// None when that leaves nothing usable, like for `..`.
+    pub fn file_name(&self) -> Option<PathBuf> {
+        let name = self.name.rsplit(['/', '\\']).next()?;
+
+        match name.trim().is_empty() || is_unsafe_filename(name) {
+            true => None,
+            false => Some(PathBuf::from(name)),
+        }
+    }
+}
+
+pub async fn read_metalink(path: &Path) -> Result<Vec<MetalinkFile>, RawstErr> {
+    let content = tokio::fs::read_to_string(path).await.map_err(RawstErr::FileError)?;
+
+    parse_metalink(&content)
+}
+
+pub fn parse_metalink(content: &str) -> Result<Vec<MetalinkFile>, RawstErr> {
+    let document = Document::parse(content).map_err(|err| RawstErr::MetalinkParse(err.to_string()))?;
+
+    let root = document.root_element();
+    if root.tag_name().name() != "metalink" {
+        return Err(RawstErr::MetalinkParse(format!("expected a <metalink> document, found <{}>", root.tag_name().name())));
+    }
+
+    let files: Vec<MetalinkFile> = root
+        .descendants()
+        .filter(|node| node.has_tag_name("file"))
+        .map(parse_file)
+        .collect::<Result<_, _>>()?;
+
+    if files.is_empty() {
+        return Err(RawstErr::MetalinkParse("no <file> entries".to_string()));
+    }
+
+    Ok(files)
+}
+
+fn parse_file(file: Node) -> Result<MetalinkFile, RawstErr> {
+    let name = file
+        .attribute("name")
+        .ok_or_else(|| RawstErr::MetalinkParse("<file> without a name".to_string()))?
+        .to_string();
+
+    let size = match child_text(file, "size") {
+        Some(size) => Some(size.parse().map_err(|_| RawstErr::MetalinkParse(format!("invalid size of {}: {:?}", name, size)))?),
+        None => None,
+    };
+
+    // Metalink 4 ranks by `priority` (1 is best), 3.0 by `preference` (100 is best)
+    let mut urls = Vec::new();
+    for url in file.descendants().filter(|node| node.has_tag_name("url")) {
+        let rank = match (url.attribute("priority"), url.attribute("preference")) {
+            (Some(priority), _) => priority.parse().unwrap_or(i64::MAX),
+            (None, Some(preference)) => preference.parse().map_or(i64::MAX, |preference: i64| -preference),
+            (None, None) => i64::MAX,
+        };
+
+        let text = url.text().unwrap_or_default().trim();
+        match IriString::try_from(text) {
+            Ok(iri) => urls.push((rank, iri)),
+            // Torrents and other schemes we can't use
+            Err(_) => log::warn!("Skipping invalid URL {:?} of {}", text, name),
+        }
+    }
+    urls.sort_by_key(|(rank, _)| *rank);
+    let urls: Vec<IriString> = urls.into_iter().map(|(_, iri)| iri).collect();
+
+    if urls.is_empty() {
+        return Err(RawstErr::MetalinkParse(format!("no URLs for {}", name)));
+    }
+
+    Ok(MetalinkFile {
+        checksum: strongest_hash(file),
+        name,
+        size,
+        urls,
+    })
+}
+
+fn child_text<'a>(node: Node<'a, '_>, tag: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.has_tag_name(tag))
+        .and_then(|child| child.text())
+        .map(str::trim)
+}
+
+fn strongest_hash(file: Node) -> Option<Checksum> {
+    let hashes: Vec<(&str, String)> = file
+        .descendants()
+        // Piece hashes only cover parts of the file
+        .filter(|node| node.has_tag_name("hash") && !node.parent().is_some_and(|parent| parent.has_tag_name("pieces")))
+        .filter_map(|node| Some((node.attribute("type")?, node.text()?.trim().to_lowercase())))
+        .collect();
+
+    let find = |types: &[&str]| {
+        hashes
+            .iter()
+            .find(|(kind, _)| types.iter().any(|wanted| kind.eq_ignore_ascii_case(wanted)))
+            .map(|(_, digest)| digest.clone())
+    };
+
+    find(&["sha-256", "sha256"])
+        .map(Checksum::Sha256)
+        .or_else(|| find(&["sha-1", "sha1"]).map(Checksum::Sha1))
+        .or_else(|| find(&["md5"]).map(Checksum::Md5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iris(urls: &[&str]) -> Vec<IriString> {
+        urls.iter().map(|url| IriString::try_from(*url).unwrap()).collect()
+    }
+
+    fn file_named(name: &str) -> MetalinkFile {
+        MetalinkFile { name: name.to_string(), size: None, urls: Vec::new(), checksum: None }
+    }
+
+    #[test]
+    fn metalink_4_files_are_read_in_priority_order() {
+        let files = parse_metalink(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+                <file name="example.iso">
+                    <size>14471447</size>
+                    <hash type="sha-256">F2CA1BB6C7E907D06DAFE4687E579FCE76B37E4E93B7605022DA52E6CCC26FD2</hash>
+                    <url priority="2">http://mirror.example.com/example.iso</url>
+                    <url>http://slow.example.com/example.iso</url>
+                    <url priority="1">http://example.com/example.iso</url>
+                </file>
+            </metalink>"#).unwrap();
+
+        assert_eq!(files, vec![MetalinkFile {
+            name: "example.iso".to_string(),
+            size: Some(14471447),
+            urls: iris(&["http://example.com/example.iso", "http://mirror.example.com/example.iso", "http://slow.example.com/example.iso"]),
+            checksum: Some(Checksum::Sha256("f2ca1bb6c7e907d06dafe4687e579fce76b37e4e93b7605022da52e6ccc26fd2".to_string())),
+        }]);
+    }
+
+    #[test]
+    fn metalink_3_files_are_read_in_preference_order() {
+        let files = parse_metalink(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink version="3.0" xmlns="http://www.metalinker.org/">
+                <files>
+                    <file name="first.iso">
+                        <size>1024</size>
+                        <verification>
+                            <hash type="md5">d41d8cd98f00b204e9800998ecf8427e</hash>
+                        </verification>
+                        <resources>
+                            <url type="http" preference="10">http://slow.example.com/first.iso</url>
+                            <url type="http" preference="100">http://example.com/first.iso</url>
+                        </resources>
+                    </file>
+                    <file name="second.iso">
+                        <resources>
+                            <url type="http" preference="50">http://example.com/second.iso</url>
+                        </resources>
+                    </file>
+                </files>
+            </metalink>"#).unwrap();
+
+        assert_eq!(files, vec![
+            MetalinkFile {
+                name: "first.iso".to_string(),
+                size: Some(1024),
+                urls: iris(&["http://example.com/first.iso", "http://slow.example.com/first.iso"]),
+                checksum: Some(Checksum::Md5("d41d8cd98f00b204e9800998ecf8427e".to_string())),
+            },
+            MetalinkFile {
+                name: "second.iso".to_string(),
+                size: None,
+                urls: iris(&["http://example.com/second.iso"]),
+                checksum: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn the_strongest_whole_file_hash_is_picked() {
+        let checksum = |hashes: &str| {
+            let document = format!(r#"<metalink><file name="f"><url>http://example.com/f</url>{hashes}</file></metalink>"#);
+            parse_metalink(&document).unwrap().remove(0).checksum
+        };
+
+        assert_eq!(
+            checksum(r#"<hash type="md5">aa</hash><hash type="sha-256">cc</hash><hash type="sha-1">bb</hash>"#),
+            Some(Checksum::Sha256("cc".to_string())),
+        );
+        assert_eq!(checksum(r#"<hash type="md5">aa</hash><hash type="SHA1">bb</hash>"#), Some(Checksum::Sha1("bb".to_string())));
+        // Piece hashes don't cover the whole file
+        assert_eq!(
+            checksum(r#"<pieces length="1024" type="sha-256"><hash type="sha-256">cc</hash></pieces><hash type="md5">aa</hash>"#),
+            Some(Checksum::Md5("aa".to_string())),
+        );
+        assert_eq!(checksum(r#"<hash type="crc32">dd</hash>"#), None);
+    }
+
+    #[test]
+    fn malformed_metalinks_are_rejected() {
+        for document in [
+            "<metalink><file name=\"f\">",
+            "not xml at all",
+            "<html><body>Not found</body></html>",
+            "<metalink></metalink>",
+            "<metalink><file><url>http://example.com/f</url></file></metalink>",
+            "<metalink><file name=\"f\"><size>big</size><url>http://example.com/f</url></file></metalink>",
+            "<metalink><file name=\"f\"></file></metalink>",
+        ] {
+            let result = parse_metalink(document);
+            assert!(matches!(result, Err(RawstErr::MetalinkParse(_))), "{:?} gave {:?}", document, result);
+        }
+    }
+
+    #[test]
+    fn files_are_saved_without_their_directories() {
+        assert_eq!(file_named("example.iso").file_name(), Some(PathBuf::from("example.iso")));
+        assert_eq!(file_named("isos/example.iso").file_name(), Some(PathBuf::from("example.iso")));
+        assert_eq!(file_named("../../.bashrc").file_name(), Some(PathBuf::from(".bashrc")));
+        assert_eq!(file_named("C:\\isos\\example.iso").file_name(), Some(PathBuf::from("example.iso")));
+
+        for name in ["", "isos/", "isos/..", "."] {
+            assert_eq!(file_named(name).file_name(), None, "{:?} has no name to save as", name);
+        }
+    }
+}
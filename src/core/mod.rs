@@ -1,9 +1,23 @@
+pub mod backend;
+pub mod checksum;
 pub mod config;
+pub mod downloader;
 pub mod engine;
 pub mod errors;
+pub mod file;
+pub mod ftp;
 pub mod history;
+pub mod hooks;
 pub mod http_handler;
+pub mod interrupt;
 pub mod io;
 pub mod logger;
+pub mod manifest;
+pub mod metalink;
+pub mod output;
+pub mod progress;
+pub mod rate_limiter;
 pub mod task;
+#[cfg(test)]
+pub mod testing;
 pub mod utils;
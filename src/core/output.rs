@@ -0,0 +1,190 @@
+use std::fmt::Display;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::core::engine::{DownloadPlan, DownloadStats, DownloadSummary};
+use crate::core::errors::RawstErr;
+use crate::core::io::StaleChunk;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static JSON: AtomicBool = AtomicBool::new(false);
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
+// Downloads of a batch would otherwise ask over each other
+static PROMPT: Mutex<()> = Mutex::const_new(());
+
+/// Silences status messages and progress bars for the rest of the program
+///
+/// Results a command was asked for (history listings, batch summaries) and
+/// errors are still printed.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::SeqCst);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::SeqCst)
+}
+
+/// Moves messages and results to stderr, stdout carries the download itself with `-o -`
+pub fn set_stdout_is_data(stdout_is_data: bool) {
+    STDOUT_IS_DATA.store(stdout_is_data, Ordering::SeqCst);
+}
+
+pub fn stdout_is_data() -> bool {
+    STDOUT_IS_DATA.load(Ordering::SeqCst)
+}
+
+// Like println!, but on stderr when stdout is taken by the download
+macro_rules! say {
+    ($($arg:tt)*) => {
+        match stdout_is_data() {
+            true => eprintln!($($arg)*),
+            false => println!($($arg)*),
+        }
+    };
+}
+
+pub fn set_emitter(emitter: EventEmitter) {
+    JSON.store(emitter == EventEmitter::Json, Ordering::SeqCst);
+}
+
+pub fn emitter() -> EventEmitter {
+    match JSON.load(Ordering::SeqCst) {
+        true => EventEmitter::Json,
+        false => EventEmitter::Human,
+    }
+}
+
+/// Prints a message about what rawst is doing, unless `--quiet` was given
+pub fn status(message: impl Display) {
+    emitter().emit(Event::Message { text: message.to_string() });
+}
+
+/// Asks a yes or no question on stderr and reads the answer from stdin, no unless it's `y` or `yes`
+///
+/// Printed even with `--quiet`, the answer is needed to go on.
+pub async fn confirm(question: String) -> Result<bool, RawstErr> {
+    let _asking = PROMPT.lock().await;
+
+    let answer = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut stderr = std::io::stderr();
+        write!(stderr, "{} [y/N] ", question)?;
+        stderr.flush()?;
+
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(answer)
+    })
+    .await
+    .map_err(|err| RawstErr::FileError(err.into()))?
+    .map_err(RawstErr::FileError)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Something that happened while downloading
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Message { text: String },
+    Started { url: String, file: String, bytes: u64, total: Option<u64> },
+    Progress { url: String, bytes: u64, total: Option<u64> },
+    Completed { url: String, bytes: u64 },
+    Failed { url: String, bytes: u64, error: String },
+    /// A download is saved, and how it went
+    Downloaded(DownloadSummary),
+    /// How a saved download was split, with `--stats`
+    Stats(DownloadStats),
+    /// What a download would do, with `--dry-run`
+    Plan(DownloadPlan),
+    BatchFinished { succeeded: usize, failed: Vec<BatchFailure> },
+    /// A file still matches the checksum recorded when it was downloaded, for `rawst verify`
+    Verified { path: PathBuf, checksum: String },
+    /// Stale chunks removed by `rawst clean`, or only found with `--dry-run`
+    Cleaned { removed: Vec<StaleChunk>, bytes: u64, dry_run: bool },
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchFailure {
+    pub url: String,
+    pub error: String,
+}
+
+/// How events reach the user
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventEmitter {
+    /// Text for people, progress is left to the bars
+    #[default]
+    Human,
+    /// One JSON object per line on stdout, for other programs. On stderr with `-o -`
+    Json,
+}
+
+impl EventEmitter {
+    pub fn emit(self, event: Event) {
+        match self {
+            // Like the text, messages are left out with `--quiet`
+            EventEmitter::Json if is_quiet() && matches!(event, Event::Message { .. }) => {}
+            EventEmitter::Json => say!("{}", serde_json::to_string(&event).unwrap()),
+            EventEmitter::Human => match event {
+                Event::Message { text } if !is_quiet() => say!("{}", text),
+                // Skipped downloads were already reported as such
+                Event::Downloaded(summary) if !is_quiet() && summary.segments > 0 => say!(
+                    "Saved {} ({} bytes in {:.2}s, {:.2} MB/s)",
+                    summary.path.display(),
+                    summary.bytes,
+                    summary.duration.as_secs_f64(),
+                    summary.avg_bps / 1_000_000.0,
+                ),
+                // Asked for, shown even with `--quiet`
+                Event::Stats(stats) => {
+                    say!("Segments:    {}", stats.segments.len());
+                    for segment in &stats.segments {
+                        say!(
+                            "  #{:<3} {:<23} {:>12} bytes  {:>8.2}s  {:>8.2} MB/s",
+                            segment.index,
+                            segment.range,
+                            segment.bytes,
+                            segment.elapsed.as_secs_f64(),
+                            segment.avg_bps / 1_000_000.0,
+                        );
+                    }
+                    say!("Wall time:   {:.2}s", stats.wall_time.as_secs_f64());
+                    say!("Throughput:  {:.2} MB/s ({} bytes)", stats.avg_bps / 1_000_000.0, stats.bytes);
+                }
+                Event::Plan(plan) => {
+                    say!("URL:         {}", plan.url);
+                    for mirror in &plan.mirrors {
+                        say!("Mirror:      {}", mirror);
+                    }
+                    say!("File:        {}", plan.file.display());
+                    match plan.bytes {
+                        Some(bytes) => say!("Size:        {} bytes", bytes),
+                        None => say!("Size:        unknown"),
+                    }
+                    say!("Ranges:      {}", if plan.supports_ranges { "supported" } else { "not supported" });
+                    say!("Threads:     {}", plan.threads);
+                    say!("Destination: {}", plan.destination.display());
+                }
+                Event::Verified { path, checksum } if !is_quiet() => say!("{}: OK ({})", path.display(), checksum),
+                Event::Cleaned { removed, bytes, dry_run: true } if !is_quiet() => {
+                    for chunk in removed {
+                        say!("Would remove {} ({} bytes)", chunk.path.display(), chunk.bytes);
+                    }
+                    say!("{} bytes would be freed", bytes);
+                }
+                Event::Cleaned { bytes, dry_run: false, .. } if !is_quiet() => say!("Freed {} bytes", bytes),
+                Event::BatchFinished { succeeded, failed } => {
+                    say!("{} succeeded, {} failed", succeeded, failed.len());
+                    for failure in failed {
+                        say!("  {}: {}", failure.url, failure.error);
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indicatif::{DecimalBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::{Deserialize, Serialize};
+
+use crate::core::errors::RawstErr;
+use crate::core::output::{Event, EventEmitter};
+use crate::core::utils::SpeedTracker;
+
+// Speeds are averaged over this much recent history
+const SPEED_WINDOW: Duration = Duration::from_secs(3);
+// How often the spinner of downloads of unknown size moves
+const SPINNER_TICK: Duration = Duration::from_millis(120);
+// At most one progress event per download this often in JSON mode
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How the bars estimate the time left
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtaMode {
+    /// From the speed of the whole download
+    #[default]
+    Aggregate,
+    /// From the segment that will finish last at its own speed, steadier as segments finish
+    Slowest,
+}
+
+/// Receives the progress of every download
+///
+/// Keeps the engine independent of how progress is shown, the terminal bars
+/// below are one implementation.
+pub trait ProgressReporter: Send + Sync {
+    /// Registers a download of `total` bytes (`None` if unknown) with `done` bytes already there
+    fn add(&self, url: &str, name: &str, total: Option<u64>, done: u64) -> Box<dyn DownloadProgress>;
+}
+
+/// Progress of a single download
+pub trait DownloadProgress: Send + Sync {
+    /// Total bytes of the download written so far
+    fn set_position(&self, done: u64);
+    /// Size of a download that started with an unknown one
+    fn set_length(&self, total: u64);
+    /// Bytes of one segment of a concurrent download written so far, out of `total`
+    ///
+    /// Reported before the position of the whole download it adds up to.
+    fn set_segment(&self, _segment: usize, _done: u64, _total: u64) {}
+    fn finish(&self);
+    fn fail(&self, err: &RawstErr);
+}
+
+/// One terminal bar per download, plus an overall bar once there's more than one
+pub struct BarReporter {
+    multi_bar: MultiProgress,
+    overall: ProgressBar,
+    downloads: Mutex<usize>,
+    eta_mode: EtaMode,
+}
+
+impl BarReporter {
+    pub fn new() -> Self {
+        Self::with_eta_mode(EtaMode::default())
+    }
+
+    pub fn with_eta_mode(eta_mode: EtaMode) -> Self {
+        // Hidden until it joins the multi bar, a lone bar would draw itself
+        let overall = ProgressBar::with_draw_target(Some(0), ProgressDrawTarget::hidden()).with_message("Total");
+        overall.set_style(
+            ProgressStyle::with_template("{msg} | {bytes}/{total_bytes} | [{wide_bar:.cyan/white}] | {eta} | [{decimal_bytes_per_sec}]")
+                .unwrap()
+                .progress_chars("=>_"),
+        );
+
+        BarReporter {
+            multi_bar: MultiProgress::new(),
+            overall,
+            downloads: Mutex::new(0),
+            eta_mode,
+        }
+    }
+}
+
+impl Default for BarReporter {
+    fn default() -> Self {
+        BarReporter::new()
+    }
+}
+
+impl ProgressReporter for BarReporter {
+    fn add(&self, _url: &str, name: &str, total: Option<u64>, done: u64) -> Box<dyn DownloadProgress> {
+        let bar = ProgressBar::new(total.unwrap_or(0)).with_message(name.to_string());
+        match total {
+            Some(_) => bar.set_style(bar_style()),
+            None => {
+                bar.set_style(spinner_style());
+                bar.enable_steady_tick(SPINNER_TICK);
+            }
+        }
+        bar.set_position(done);
+        bar.reset_eta();
+
+        let mut downloads = self.downloads.lock().unwrap();
+        *downloads += 1;
+
+        // The overall bar only shows up with the second download and stays last
+        let bar = match *downloads {
+            1 => self.multi_bar.add(bar),
+            2 => {
+                let bar = self.multi_bar.add(bar);
+                self.multi_bar.add(self.overall.clone());
+                bar
+            }
+            _ => self.multi_bar.insert_before(&self.overall, bar),
+        };
+
+        self.overall.inc_length(total.unwrap_or(0));
+        self.overall.inc(done);
+
+        Box::new(Bar {
+            bar,
+            overall: self.overall.clone(),
+            reported: AtomicU64::new(done),
+            total: Mutex::new(total),
+            speed: Mutex::new(SpeedTracker::new(SPEED_WINDOW)),
+            eta_mode: self.eta_mode,
+            segments: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+/// Shows nothing, for `--quiet`
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {
+    fn add(&self, _url: &str, _name: &str, _total: Option<u64>, _done: u64) -> Box<dyn DownloadProgress> {
+        Box::new(NoProgress)
+    }
+}
+
+impl DownloadProgress for NoProgress {
+    fn set_position(&self, _done: u64) {}
+    fn set_length(&self, _total: u64) {}
+    fn finish(&self) {}
+    fn fail(&self, _err: &RawstErr) {}
+}
+
+/// JSON lines on stdout for `--json`, stderr with `-o -`
+pub struct JsonReporter;
+
+impl ProgressReporter for JsonReporter {
+    fn add(&self, url: &str, name: &str, total: Option<u64>, done: u64) -> Box<dyn DownloadProgress> {
+        EventEmitter::Json.emit(Event::Started { url: url.to_string(), file: name.to_string(), bytes: done, total });
+
+        Box::new(JsonProgress {
+            url: url.to_string(),
+            total: Mutex::new(total),
+            done: AtomicU64::new(done),
+            last_event: Mutex::new(Instant::now()),
+        })
+    }
+}
+
+struct JsonProgress {
+    url: String,
+    total: Mutex<Option<u64>>,
+    done: AtomicU64,
+    last_event: Mutex<Instant>,
+}
+
+impl DownloadProgress for JsonProgress {
+    fn set_position(&self, done: u64) {
+        self.done.store(done, Ordering::SeqCst);
+
+        // Chunks arrive every few KiB, a line each would flood the reader
+        let mut last_event = self.last_event.lock().unwrap();
+        if last_event.elapsed() < JSON_PROGRESS_INTERVAL {
+            return;
+        }
+        *last_event = Instant::now();
+
+        let total = *self.total.lock().unwrap();
+        EventEmitter::Json.emit(Event::Progress { url: self.url.clone(), bytes: done, total });
+    }
+
+    fn set_length(&self, total: u64) {
+        *self.total.lock().unwrap() = Some(total);
+    }
+
+    fn finish(&self) {
+        EventEmitter::Json.emit(Event::Completed { url: self.url.clone(), bytes: self.done.load(Ordering::SeqCst) });
+    }
+
+    fn fail(&self, err: &RawstErr) {
+        EventEmitter::Json.emit(Event::Failed {
+            url: self.url.clone(),
+            bytes: self.done.load(Ordering::SeqCst),
+            error: err.to_string(),
+        });
+    }
+}
+
+struct Bar {
+    bar: ProgressBar,
+    overall: ProgressBar,
+    // Position last added to the overall bar
+    reported: AtomicU64,
+    total: Mutex<Option<u64>>,
+    speed: Mutex<SpeedTracker>,
+    eta_mode: EtaMode,
+    // Only filled in by concurrent downloads
+    segments: Mutex<Vec<SegmentSpeed>>,
+}
+
+struct SegmentSpeed {
+    done: u64,
+    total: u64,
+    speed: SpeedTracker,
+}
+
+// Time until the last unfinished segment is done, `None` while one of them isn't moving
+fn slowest_eta(segments: &[SegmentSpeed]) -> Option<Duration> {
+    segments
+        .iter()
+        .filter(|segment| segment.done < segment.total)
+        .map(|segment| segment.speed.eta(Some(segment.total), segment.done))
+        .try_fold(Duration::ZERO, |slowest, eta| Some(slowest.max(eta?)))
+}
+
+// Speed and ETA come from a SpeedTracker through the prefix
+fn bar_style() -> ProgressStyle {
+    ProgressStyle::with_template("{msg} | {bytes}/{total_bytes} | [{wide_bar:.green/white}] | {prefix}")
+        .unwrap()
+        .progress_chars("=>_")
+}
+
+// Without a total there's no percentage nor ETA to show
+fn spinner_style() -> ProgressStyle {
+    ProgressStyle::with_template("{spinner:.green} {msg} | {bytes} | {prefix}").unwrap()
+}
+
+impl DownloadProgress for Bar {
+    fn set_position(&self, done: u64) {
+        self.bar.set_position(done);
+
+        let total = *self.total.lock().unwrap();
+        let (bps, eta) = {
+            let mut speed = self.speed.lock().unwrap();
+            speed.record(done);
+            (speed.current_bps(), speed.eta(total, done))
+        };
+        let eta = match self.eta_mode {
+            EtaMode::Slowest => {
+                let segments = self.segments.lock().unwrap();
+                match segments.is_empty() {
+                    true => eta,
+                    false => slowest_eta(&segments),
+                }
+            }
+            EtaMode::Aggregate => eta,
+        };
+        let speed = format!("[{}/s]", DecimalBytes(bps as u64));
+        match total {
+            Some(_) => {
+                let eta = eta.map_or("--".to_string(), |eta| format!("{:#}", HumanDuration(eta)));
+                self.bar.set_prefix(format!("{} | {}", eta, speed));
+            }
+            None => self.bar.set_prefix(speed),
+        }
+
+        // Positions can go back when a download restarts from scratch
+        let previous = self.reported.swap(done, Ordering::SeqCst);
+        if done >= previous {
+            self.overall.inc(done - previous);
+        } else {
+            self.overall.set_position(self.overall.position().saturating_sub(previous - done));
+        }
+    }
+
+    fn set_segment(&self, segment: usize, done: u64, total: u64) {
+        if self.eta_mode != EtaMode::Slowest {
+            return;
+        }
+
+        let mut segments = self.segments.lock().unwrap();
+        while segments.len() <= segment {
+            segments.push(SegmentSpeed { done: 0, total: 0, speed: SpeedTracker::new(SPEED_WINDOW) });
+        }
+
+        let segment = &mut segments[segment];
+        segment.done = done;
+        segment.total = total;
+        segment.speed.record(done);
+    }
+
+    fn set_length(&self, total: u64) {
+        *self.total.lock().unwrap() = Some(total);
+
+        self.bar.disable_steady_tick();
+        self.bar.set_style(bar_style());
+        self.bar.set_length(total);
+        self.overall.inc_length(total);
+    }
+
+    fn finish(&self) {
+        // finish() jumps to the length, which is 0 for spinners
+        if self.total.lock().unwrap().is_none() {
+            self.bar.set_length(self.bar.position());
+        }
+        self.bar.finish();
+    }
+
+    fn fail(&self, _err: &RawstErr) {
+        self.bar.abandon_with_message(format!("{} (failed)", self.bar.message()));
+    }
+}
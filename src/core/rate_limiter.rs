@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Token bucket shared by every segment of the downloads it limits
+///
+/// Segments acquire the size of each received chunk before writing it.
+/// The bucket is allowed to go into debt, later callers wait until the
+/// debt is paid back, which keeps the aggregate rate under the limit.
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second.max(1);
+
+        RateLimiter {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let rate = self.bytes_per_second as f64;
+            let mut bucket = self.bucket.lock().unwrap();
+
+            // Refills for the elapsed time, holding at most one second worth of bytes
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Caps the connections open to each host, across every download sharing it
+///
+/// Each request holds a permit of its host until its body is read, so
+/// segments and batch downloads to one host queue up while other hosts
+/// aren't affected.
+pub struct HostLimiter {
+    max_connections: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    pub fn new(max_connections: usize) -> Self {
+        HostLimiter {
+            max_connections: max_connections.max(1),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host.to_ascii_lowercase())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_connections)))
+            .clone();
+
+        // The semaphores are never closed
+        semaphore.acquire_owned().await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn a_busy_host_doesnt_hold_up_other_hosts() {
+        let limiter = HostLimiter::new(2);
+
+        let held = [limiter.acquire("a.example.com").await, limiter.acquire("a.example.com").await];
+
+        // Hosts are the same whatever their case
+        assert!(limiter.acquire("A.example.com").now_or_never().is_none());
+        assert!(limiter.acquire("b.example.com").now_or_never().is_some());
+
+        drop(held);
+        assert!(limiter.acquire("a.example.com").now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn a_waiting_connection_gets_the_released_permit() {
+        let limiter = Arc::new(HostLimiter::new(1));
+        let held = limiter.acquire("a.example.com").await;
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("a.example.com").await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(held);
+        let _permit = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tasks_sharing_a_limiter_stay_under_its_rate() {
+        const RATE: u64 = 100_000;
+        const TASKS: u64 = 4;
+        const CHUNK: u64 = 16 * 1024;
+        const CHUNKS_PER_TASK: u64 = 40;
+
+        let limiter = Arc::new(RateLimiter::new(RATE));
+        let started = Instant::now();
+
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    for _ in 0..CHUNKS_PER_TASK {
+                        limiter.acquire(CHUNK).await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let total = TASKS * CHUNKS_PER_TASK * CHUNK;
+        let elapsed = started.elapsed().as_secs_f64();
+        // The bucket starts with one second worth of bytes
+        let allowed = RATE as f64 * (elapsed + 1.0);
+        assert!(total as f64 <= allowed, "{} bytes in {:.2}s is over {} B/s", total, elapsed, RATE);
+        // And the limit doesn't slow things down much further
+        assert!(elapsed < (total / RATE) as f64 + 2.0, "{} bytes took {:.2}s", total, elapsed);
+    }
+
+}
@@ -2,16 +2,38 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, Mutex,
 };
+use std::time::Duration;
 
 use iri_string::types::IriString;
-use reqwest::header::HeaderMap;
+use reqwest::header::{HeaderMap, HeaderName, ETAG, LAST_MODIFIED};
 use chrono::prelude::{Local, DateTime};
 use sha2::{Sha256, Digest};
 
+use crate::core::backend::ResourceMeta;
+use crate::core::errors::RawstErr;
+use crate::core::io::Checksum;
+
+/// Bytes `start` to `end` of a file, inclusive like HTTP ranges, up to its end without `end`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl std::fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}-{}", self.start, end),
+            None => write!(f, "{}-", self.start),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Chunk {
+    pub start: u64, // byte where the chunk begins in the output file
     pub x_offset: u64, // x offset is starting byte
     pub y_offset: u64, // y offset is end byte
 
@@ -21,6 +43,7 @@ pub struct Chunk {
 impl Chunk {
     pub fn new(x_offset: u64, y_offset: u64) -> Self {
         Chunk {
+            start: x_offset,
             x_offset,
             y_offset,
             downloaded: Arc::new(AtomicU64::new(0)),
@@ -34,6 +57,60 @@ impl Chunk {
     }
 }
 
+/// How one segment of a download went, for `--stats`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentStats {
+    pub index: usize,
+    pub range: ByteRange,
+    // Only the bytes of this run, a resumed segment had the rest already
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl SegmentStats {
+    pub fn bytes_per_second(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// What a response says about the version of the resource it carries
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: HeaderName| headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+
+        Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Whether both are of the same version, comparing the ETags if both have one
+    pub fn matches(&self, other: &Validators) -> bool {
+        if let (Some(etag), Some(other_etag)) = (&self.etag, &other.etag) {
+            return etag == other_etag;
+        }
+
+        match (&self.last_modified, &other.last_modified) {
+            (Some(last_modified), Some(other_last_modified)) => last_modified == other_last_modified,
+            _ => true,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ChunkType {
     Single(Chunk),
@@ -44,11 +121,23 @@ pub enum ChunkType {
 #[derive(Clone)]
 pub struct HttpTask {
     pub iri: IriString,
+    // Other sources for the same file, tried after `iri`
+    pub mirrors: Vec<IriString>,
     pub filename: PathBuf,
     pub total_downloaded: Arc<AtomicU64>,
     pub chunk_data: ChunkType,
     pub additional_headers: HashMap<String, String>,
     pub timestamp: DateTime<Local>,
+    // Verified before the file is moved to its final path
+    pub checksum: Option<Checksum>,
+    // Downloads stop once they get past it, whatever size the server announced
+    pub max_size: Option<u64>,
+    // Known before the request, from a metalink, checked like the Content-Length
+    pub expected_size: Option<u64>,
+    // Filled in as segments complete, shared by the clones of the task
+    pub segment_stats: Arc<Mutex<Vec<SegmentStats>>>,
+    // Of the first segment response from each source, the later ones must match them
+    segment_validators: Arc<Mutex<HashMap<String, Validators>>>,
 
     // Cached headermap from Head request
     // Efficient for header values retrieval
@@ -78,16 +167,42 @@ impl HttpTask {
 
         HttpTask {
             iri,
+            mirrors: Vec::new(),
             filename,
             headers: cached_headers,
             total_downloaded: Arc::new(AtomicU64::new(0)),
             chunk_data,
             additional_headers,
             timestamp: Local::now(),
+            checksum: None,
+            max_size: None,
+            expected_size: None,
+            segment_stats: Arc::new(Mutex::new(Vec::new())),
+            segment_validators: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub fn hashed_file_name(&self) -> String {
+    /// Fails once more than `max_size` bytes were downloaded
+    pub fn check_size_limit(&self) -> Result<(), RawstErr> {
+        match self.max_size {
+            Some(limit) if self.total_downloaded.load(Ordering::SeqCst) > limit => Err(RawstErr::SizeLimitExceeded { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Source to use for the nth request, cycling through `iri` and the mirrors
+    pub fn source(&self, n: usize) -> &IriString {
+        match n % (self.mirrors.len() + 1) {
+            0 => &self.iri,
+            i => &self.mirrors[i - 1],
+        }
+    }
+
+    /// Id naming the temporary files of this download, chunks and partial output alike
+    ///
+    /// Hashes the URL with the time the download started, which resuming
+    /// restores, so downloads sharing a name never share temporary files.
+    pub fn download_id(&self) -> String {
 
         let formatted_string = format!("{}{}", self.iri, self.timestamp);
 
@@ -165,21 +280,249 @@ impl HttpTask {
         }
     }
 
+    /// Byte offsets where each chunk begins in the output file
+    pub fn chunk_offsets(&self) -> Vec<u64> {
+        match &self.chunk_data {
+            ChunkType::Single(chunk) => vec![chunk.start],
+            ChunkType::Multiple(chunks) => chunks.iter().map(|chunk| chunk.start).collect(),
+            ChunkType::None => Vec::new(),
+        }
+    }
+
+    /// Bytes in `chunk`, the end of the last one is past the end of the file
+    pub fn chunk_length(&self, chunk: &Chunk) -> u64 {
+        (chunk.y_offset + 1).min(self.content_length()).saturating_sub(chunk.start)
+    }
+
+    /// Bytes of the file `chunk` covers
+    pub fn chunk_range(&self, chunk: &Chunk) -> ByteRange {
+        ByteRange {
+            start: chunk.start,
+            end: Some(chunk.start + self.chunk_length(chunk).saturating_sub(1)),
+        }
+    }
+
+    pub fn record_segment(&self, stats: SegmentStats) {
+        self.segment_stats.lock().unwrap().push(stats);
+    }
+
+    /// Checks a segment response from `source` is of the same version as the first one
+    ///
+    /// Segments of different versions would be merged into a corrupt file.
+    /// Each source is compared with itself, as mirrors have validators of their own.
+    pub fn check_segment(&self, source: &IriString, headers: &HeaderMap) -> Result<(), RawstErr> {
+        let validators = Validators::from_headers(headers);
+        if validators.is_empty() {
+            return Ok(());
+        }
+
+        let mut segment_validators = self.segment_validators.lock().unwrap();
+        match segment_validators.get(source.as_str()) {
+            Some(first) if !first.matches(&validators) => {
+                log::warn!("{} changed between segments, from {:?} to {:?}", source, first, validators);
+                Err(RawstErr::ResourceChanged(source.to_string()))
+            }
+            Some(_) => Ok(()),
+            None => {
+                segment_validators.insert(source.to_string(), validators);
+                Ok(())
+            }
+        }
+    }
+
+    /// Header of the preflight response, if it's there and readable
+    pub fn response_header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    /// What the preflight response says about the resource
+    pub fn meta(&self) -> ResourceMeta {
+        ResourceMeta::from_headers(&self.headers)
+    }
+
+    /// Size announced by the server, `None` for chunked responses without a Content-Length
+    ///
+    /// A Content-Length that isn't a number is as good as none.
+    pub fn known_length(&self) -> Option<u64> {
+        self.headers.get("content-length")
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok())
+    }
+
+    /// Size the finished file must have, the one given to the task before the server's
+    pub fn expected_length(&self) -> Option<u64> {
+        self.expected_size.or_else(|| self.known_length())
+    }
+
     pub fn content_length(&self) -> u64 {
-        match self.headers.get("content-length") {
-            Some(length) => length
-                .to_str()
-                .unwrap()
-                .parse()
-                .expect("Invalid size format"),
-            None => 0,
+        self.known_length().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use reqwest::header::CONTENT_LENGTH;
+
+    use super::*;
+    use crate::core::io::partial_download_path;
+    use crate::core::utils::chunk_file_name;
+
+    fn task(url: &str, filename: &str) -> HttpTask {
+        HttpTask::new(IriString::try_from(url).unwrap(), PathBuf::from(filename), HeaderMap::new(), HashMap::new())
+    }
+
+    // Every temporary file the task could write, partial output and chunks alike
+    fn temporary_paths(task: &HttpTask, threads: usize) -> HashSet<PathBuf> {
+        let dir = Path::new("/srv/downloads");
+        let mut paths: HashSet<PathBuf> = (0..threads).map(|i| dir.join(chunk_file_name(task.download_id(), i))).collect();
+        paths.insert(partial_download_path(task, dir));
+
+        paths
+    }
+
+    #[test]
+    fn same_name_from_different_urls_share_no_temporary_file() {
+        let first = task("http://example.com/a/file.iso", "file.iso");
+        let mut second = task("http://mirror.example.org/b/file.iso", "file.iso");
+        second.timestamp = first.timestamp;
+
+        assert_ne!(first.download_id(), second.download_id());
+        assert!(temporary_paths(&first, 8).is_disjoint(&temporary_paths(&second, 8)));
+    }
+
+    #[test]
+    fn same_url_started_at_different_times_share_no_temporary_file() {
+        let first = task("http://example.com/file.iso", "file.iso");
+        let mut second = first.clone();
+        second.timestamp = first.timestamp + chrono::Duration::seconds(1);
+
+        assert_ne!(first.download_id(), second.download_id());
+        assert!(temporary_paths(&first, 8).is_disjoint(&temporary_paths(&second, 8)));
+    }
+
+    #[test]
+    fn a_resumed_download_finds_its_temporary_files() {
+        let first = task("http://example.com/file.iso", "file.iso");
+        let mut resumed = task("http://example.com/file.iso", "file.iso");
+        resumed.timestamp = first.timestamp;
+
+        assert_eq!(temporary_paths(&first, 8), temporary_paths(&resumed, 8));
+    }
+
+    fn validators(etag: Option<&str>, last_modified: Option<&str>) -> Validators {
+        Validators {
+            etag: etag.map(str::to_string),
+            last_modified: last_modified.map(str::to_string),
+        }
+    }
+
+    fn headers(etag: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, etag.parse().unwrap());
+
+        headers
+    }
+
+    #[test]
+    fn validators_compare_etags_first() {
+        let monday = Some("Mon, 19 Oct 2015 07:28:00 GMT");
+        let tuesday = Some("Tue, 20 Oct 2015 07:28:00 GMT");
+
+        assert!(validators(Some("\"v1\""), monday).matches(&validators(Some("\"v1\""), tuesday)));
+        assert!(!validators(Some("\"v1\""), monday).matches(&validators(Some("\"v2\""), monday)));
+        // Without an ETag on both sides the dates decide
+        assert!(validators(Some("\"v1\""), monday).matches(&validators(None, monday)));
+        assert!(!validators(Some("\"v1\""), monday).matches(&validators(None, tuesday)));
+    }
+
+    #[test]
+    fn validators_without_anything_to_compare_match() {
+        let monday = Some("Mon, 19 Oct 2015 07:28:00 GMT");
+
+        assert!(validators(None, None).is_empty());
+        assert!(validators(None, None).matches(&validators(Some("\"v1\""), monday)));
+        assert!(validators(Some("\"v1\""), None).matches(&validators(None, monday)));
+    }
+
+    #[test]
+    fn a_changed_etag_from_the_same_source_is_rejected() {
+        let task = task("http://example.com/file.iso", "file.iso");
+
+        task.check_segment(&task.iri, &headers("\"v1\"")).unwrap();
+        task.check_segment(&task.iri, &headers("\"v1\"")).unwrap();
+        // Segments without validators can't be checked
+        task.check_segment(&task.iri, &HeaderMap::new()).unwrap();
+
+        match task.check_segment(&task.iri, &headers("\"v2\"")) {
+            Err(RawstErr::ResourceChanged(source)) => assert_eq!(source, "http://example.com/file.iso"),
+            other => panic!("expected ResourceChanged, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mirrors_have_validators_of_their_own() {
+        let mut task = task("http://example.com/file.iso", "file.iso");
+        task.mirrors.push(IriString::try_from("http://mirror.example.org/file.iso").unwrap());
+        let mirror = task.mirrors[0].clone();
+
+        task.check_segment(&task.iri, &headers("\"v1\"")).unwrap();
+        task.check_segment(&mirror, &headers("\"mirror-v1\"")).unwrap();
+        task.check_segment(&task.iri, &headers("\"v1\"")).unwrap();
+        task.check_segment(&mirror, &headers("\"mirror-v1\"")).unwrap();
+
+        assert!(matches!(task.check_segment(&mirror, &headers("\"v1\"")), Err(RawstErr::ResourceChanged(_))));
+    }
+
+    fn task_with_length(content_length: Option<&str>) -> HttpTask {
+        let mut headers = HeaderMap::new();
+        if let Some(content_length) = content_length {
+            headers.insert(CONTENT_LENGTH, content_length.parse().unwrap());
         }
+
+        HttpTask::new(IriString::try_from("http://example.com/file.iso").unwrap(), PathBuf::from("file.iso"), headers, HashMap::new())
+    }
+
+    #[test]
+    fn known_length_comes_from_content_length() {
+        let task = task_with_length(Some("1048576"));
+
+        assert_eq!(task.known_length(), Some(1048576));
+        assert_eq!(task.content_length(), 1048576);
+        assert_eq!(task_with_length(Some("0")).known_length(), Some(0));
+    }
+
+    #[test]
+    fn known_length_is_unknown_without_content_length() {
+        let task = task_with_length(None);
+
+        assert_eq!(task.known_length(), None);
+        assert_eq!(task.content_length(), 0);
     }
 
-    pub fn allows_partial_content(&self) -> bool {
-        match self.headers.get("accept-ranges") {
-            Some(value) => value != "none",
-            None => false,
+    #[test]
+    fn unparsable_content_lengths_are_unknown() {
+        for content_length in ["lots", "-1", "1.5", "18446744073709551616"] {
+            let task = task_with_length(Some(content_length));
+
+            assert_eq!(task.known_length(), None, "Content-Length: {}", content_length);
+            assert_eq!(task.content_length(), 0);
         }
     }
+
+    #[test]
+    fn the_size_limit_allows_up_to_max_size() {
+        let mut task = task("http://example.com/file.iso", "file.iso");
+        task.total_downloaded.store(u64::MAX, Ordering::SeqCst);
+        assert!(task.check_size_limit().is_ok(), "no limit without max_size");
+
+        task.max_size = Some(1000);
+        task.total_downloaded.store(1000, Ordering::SeqCst);
+        assert!(task.check_size_limit().is_ok());
+
+        task.total_downloaded.store(1001, Ordering::SeqCst);
+        assert!(matches!(task.check_size_limit(), Err(RawstErr::SizeLimitExceeded { limit: 1000 })));
+    }
 }
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use iri_string::types::IriString;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::core::config::Config;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory of a single test, removed with everything in it when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let name = format!("rawst-test-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst));
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A config keeping every file of rawst under `dir`
+///
+/// Parsed rather than built from [`Config::default`], which needs the XDG
+/// user directories of whoever runs the tests.
+pub fn config_in(dir: &Path) -> Config {
+    let config = format!(
+        r#"
+        config_dir = {config_dir:?}
+        config_file_path = {config_file:?}
+        cache_dir = {cache_dir:?}
+        history_file_path = {history:?}
+        log_dir = {log_dir:?}
+        download_dir = {download_dir:?}
+        threads = 1
+        "#,
+        config_dir = dir.join("config"),
+        config_file = dir.join("config").join("config.toml"),
+        cache_dir = dir.join("cache"),
+        history = dir.join("cache").join("history.json"),
+        log_dir = dir.join("logs"),
+        download_dir = dir.join("downloads"),
+    );
+    let config: Config = toml::from_str(&config).unwrap();
+
+    for dir in [&config.config_dir, &config.cache_dir, &config.log_dir, &config.download_dir] {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+
+    config
+}
+
+/// A request received by a [`MockServer`], with its header names in lowercase
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// What a [`MockServer`] answers, with a Content-Length unless [`MockResponse::without_length`]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    with_length: bool,
+    // Bytes per write and the pause before each, for slow servers
+    trickle: Option<(usize, Duration)>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+            with_length: true,
+            trickle: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Ends the body by closing the connection, like servers streaming something of unknown size
+    pub fn without_length(mut self) -> Self {
+        self.with_length = false;
+        self
+    }
+
+    /// Sends the body `bytes` at a time, waiting `pause` before each
+    pub fn trickle(mut self, bytes: usize, pause: Duration) -> Self {
+        self.trickle = Some((bytes.max(1), pause));
+        self
+    }
+}
+
+/// HTTP/1.1 server on localhost answering every request with `handler`
+///
+/// Each connection serves a single request, and the server stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        Self::listen(handler, false).await
+    }
+
+    /// Like [`MockServer::start`], speaking HTTP/2 without TLS to clients that know it beforehand
+    pub async fn start_h2c<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        Self::listen(handler, true).await
+    }
+
+    async fn listen<F>(handler: F, h2c: bool) -> Self
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+
+        let received = requests.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let received = received.clone();
+                tokio::spawn(async move {
+                    // Clients giving up halfway, as timeout tests do, are no error of the server
+                    if h2c {
+                        let _ = serve_h2c(socket, handler.as_ref(), &received).await;
+                    } else {
+                        let _ = serve(socket, handler.as_ref(), &received).await;
+                    }
+                });
+            }
+        });
+
+        MockServer { addr, requests, task }
+    }
+
+    pub fn url(&self, path: &str) -> IriString {
+        IriString::try_from(format!("http://{}{}", self.addr, path)).unwrap()
+    }
+
+    /// Every request received so far, in order
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve<F>(socket: TcpStream, handler: &F, received: &Mutex<Vec<MockRequest>>) -> std::io::Result<()>
+where
+    F: Fn(&MockRequest) -> MockResponse,
+{
+    let mut socket = BufReader::new(socket);
+
+    let mut request_line = String::new();
+    socket.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Ok(());
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let request = MockRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+    };
+    let response = handler(&request);
+    received.lock().unwrap().push(request.clone());
+
+    let mut head = format!("HTTP/1.1 {} Mock\r\nConnection: close\r\n", response.status);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if response.with_length {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    head.push_str("\r\n");
+
+    let socket = socket.get_mut();
+    socket.write_all(head.as_bytes()).await?;
+
+    if request.method != "HEAD" {
+        match response.trickle {
+            Some((bytes, pause)) => {
+                for piece in response.body.chunks(bytes) {
+                    tokio::time::sleep(pause).await;
+                    socket.write_all(piece).await?;
+                    socket.flush().await?;
+                }
+            }
+            None => socket.write_all(&response.body).await?,
+        }
+    }
+
+    socket.shutdown().await
+}
+
+async fn serve_h2c<F>(socket: TcpStream, handler: &F, received: &Mutex<Vec<MockRequest>>) -> Result<(), h2::Error>
+where
+    F: Fn(&MockRequest) -> MockResponse,
+{
+    let mut connection = h2::server::handshake(socket).await?;
+
+    // Polling for the next request also sends what was queued for the previous ones
+    while let Some(accepted) = connection.accept().await {
+        let (request, mut respond) = accepted?;
+        let request = MockRequest {
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or_default().to_string()))
+                .collect(),
+        };
+        let response = handler(&request);
+        received.lock().unwrap().push(request.clone());
+
+        let mut head = http::Response::builder().status(response.status);
+        for (name, value) in &response.headers {
+            head = head.header(name, value);
+        }
+        if response.with_length {
+            head = head.header("content-length", response.body.len());
+        }
+
+        let head_only = request.method == "HEAD" || response.body.is_empty();
+        let mut body = respond.send_response(head.body(()).unwrap(), head_only)?;
+        if !head_only {
+            body.send_data(response.body.into(), true)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,10 +1,16 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
 use std::fs;
+use std::time::{Duration, Instant};
 
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::Local;
 use iri_string::types::IriString;
 use serde_json::Value;
-use reqwest::header::HeaderMap;
+use reqwest::cookie::Jar;
+use reqwest::Url;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION};
 
 use crate::core::errors::RawstErr;
 
@@ -29,48 +35,637 @@ pub fn headers_from_file(input: PathBuf) -> Result<HashMap<String, String>, Raws
     Ok(header_map)
 }
 
+// Used when neither the headers nor the URL provide a name
+const DEFAULT_FILENAME: &str = "download";
+
+/// Checks every header is a valid HTTP header name and value
+pub fn validate_headers(headers: &HashMap<String, String>) -> Result<(), RawstErr> {
+    for (name, value) in headers {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| RawstErr::InvalidHeader(format!("'{}' is not a valid header name", name)))?;
+        HeaderValue::from_str(value)
+            .map_err(|_| RawstErr::InvalidHeader(format!("the value of '{}' is not a valid header value", name)))?;
+    }
+
+    Ok(())
+}
+
+/// `Authorization` value for HTTP Basic authentication (RFC 7617)
+pub fn basic_auth_value(user: &str, password: &str) -> String {
+    format!("Basic {}", BASE64_STANDARD.encode(format!("{}:{}", user, password)))
+}
+
+/// Adds the cookies of a Netscape cookie file (as exported by browsers and curl) to `jar`
+///
+/// Each line is `domain, include subdomains, path, secure, expiry, name, value`
+/// separated by tabs. Returns how many cookies were added, expired ones are skipped.
+pub fn load_cookie_file(path: &Path, jar: &Jar) -> Result<usize, RawstErr> {
+    let content = fs::read_to_string(path).map_err(RawstErr::FileError)?;
+    let now = Local::now().timestamp();
+    let mut added = 0;
+
+    for (number, line) in content.lines().enumerate() {
+        // curl marks HttpOnly cookies with this prefix instead of a field
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, include_subdomains, cookie_path, secure, expiry, name, value] = fields[..] else {
+            return Err(RawstErr::ConfigParse(format!("{}:{} is not a Netscape cookie line", path.display(), number + 1)));
+        };
+
+        let expiry: i64 = expiry.trim().parse().unwrap_or(0);
+        if expiry != 0 && expiry < now {
+            continue;
+        }
+
+        let host = domain.trim_start_matches('.');
+        let scheme = if secure.eq_ignore_ascii_case("TRUE") { "https" } else { "http" };
+        let Ok(url) = format!("{}://{}{}", scheme, host, cookie_path).parse::<Url>() else {
+            return Err(RawstErr::ConfigParse(format!("{}:{} has an invalid domain or path", path.display(), number + 1)));
+        };
+
+        let mut cookie = format!("{}={}; Path={}", name, value, cookie_path);
+        if include_subdomains.eq_ignore_ascii_case("TRUE") {
+            cookie.push_str(&format!("; Domain={}", host));
+        }
+        if scheme == "https" {
+            cookie.push_str("; Secure");
+        }
+
+        jar.add_cookie_str(&cookie, &url);
+        added += 1;
+    }
+
+    Ok(added)
+}
+
+/// Headers whose values must not end up in logs
+pub fn is_sensitive_header(name: &str) -> bool {
+    const SENSITIVE_HEADERS: [&str; 5] = ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"];
+
+    SENSITIVE_HEADERS.contains(&name.to_ascii_lowercase().as_str())
+}
+
 pub fn extract_filename_from_url(iri: &IriString) -> PathBuf {
     // "http://example.com/path/to/file.tar.gz?query#frag"
     // => "/path/to/file.tar.gz"
-    let file_name = iri.path_str();
-    if file_name == "/" || !file_name.contains('.') {
-        let mut domain = iri.authority_str().unwrap().to_owned();
-        domain.push_str(".html");
-        return PathBuf::from(domain)
-    
+    let path = iri.path_str();
+    if path == "/" || path.is_empty() {
+        return match iri.authority_str() {
+            Some(domain) => PathBuf::from(format!("{}.html", domain)),
+            None => PathBuf::from(DEFAULT_FILENAME),
+        };
     }
-    let full_path = PathBuf::from(file_name);
-    // => "file.tar.gz"
-    let path = PathBuf::from(full_path.file_name().unwrap());
+
+    let path = sanitize_basename(&url_basename(iri)).unwrap_or_else(|| PathBuf::from(DEFAULT_FILENAME));
 
     assert!(path.is_relative());
 
     path
 }
 
+// "http://example.com/path/to/file%20name.tar.gz?query#frag" => "file name.tar.gz"
+fn url_basename(iri: &IriString) -> String {
+    let basename = iri.path_str().rsplit('/').next().unwrap_or_default();
+
+    String::from_utf8_lossy(&percent_decode(basename)).into_owned()
+}
+
 pub fn extract_filename_from_header(headers: &HeaderMap) -> Option<PathBuf> {
-    let header_value = headers.get("Content-Disposition");
+    content_disposition_filename(headers).and_then(|filename| sanitize_basename(&filename))
+}
+
+/// The name a download would be saved under before any sanitizing, from Content-Disposition or the URL
+pub fn server_filename(headers: &HeaderMap, iri: &IriString) -> String {
+    content_disposition_filename(headers).unwrap_or_else(|| url_basename(iri))
+}
+
+/// Whether `name` needs more than [`sanitize_filename`] to be a file in the download directory
+pub fn is_unsafe_filename(name: &str) -> bool {
+    name.contains(['/', '\\'])
+        || matches!(name.trim(), "." | "..")
+        || name.chars().any(|c| c.is_control())
+}
+
+/// Whether an output path names the directory to save in instead of the file
+///
+/// Existing directories do, and paths ending with a separator, like `downloads/`.
+pub fn is_directory_output(path: &Path) -> bool {
+    path.is_dir() || path.as_os_str().to_string_lossy().ends_with(std::path::is_separator)
+}
 
-    match header_value {
-        Some(value) => {
-            let parts: Vec<&str> = value.to_str().unwrap().split(';').collect();
+/// Whether `path` is a single file name, which can't leave the directory it's joined to
+pub fn is_plain_filename(path: &Path) -> bool {
+    let mut components = path.components();
 
-            for part in parts {
-                if let Some(filename) = part.trim().strip_prefix("filename=") {
-                    let path = PathBuf::from(filename.trim_matches('"'));
-                    assert!(path.is_relative());
-                    return Some(path);
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+// The file name of a Content-Disposition header, as the server sent it
+fn content_disposition_filename(headers: &HeaderMap) -> Option<String> {
+    let header_value = headers.get(CONTENT_DISPOSITION)?;
+    let header_value = String::from_utf8_lossy(header_value.as_bytes());
+
+    let mut filename = None;
+
+    for part in header_value.split(';') {
+        let Some((name, value)) = part.trim().split_once('=') else {
+            continue;
+        };
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            // RFC 5987 extended value, preferred over the plain one
+            "filename*" => {
+                if let Some(decoded) = decode_ext_value(value.trim()) {
+                    return Some(decoded);
                 }
             }
+            "filename" => filename = Some(value.trim().trim_matches('"').to_string()),
+            _ => (),
+        }
+    }
+
+    filename
+}
 
-            None
+// Decodes `charset'language'percent-encoded` as in `UTF-8''na%C3%AFve.txt`
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let bytes = percent_decode(parts.next()?);
+
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "iso-8859-1" => Some(bytes.iter().map(|&byte| byte as char).collect()),
+        _ => None,
+    }
+}
+
+pub fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
         }
-        None => None,
+    }
+
+    decoded
+}
+
+// Keeps only the last path component so names can't point outside the download directory
+fn sanitize_basename(name: &str) -> Option<PathBuf> {
+    let basename = name.rsplit(['/', '\\']).next()?.trim();
+
+    match basename {
+        "" | "." | ".." => None,
+        basename => Some(PathBuf::from(sanitize_filename(basename))),
     }
 }
 
+/// Expands the placeholders of a `filename_template`
+///
+/// `{name}` is the full resolved file name, `{stem}` and `{ext}` its parts,
+/// `{host}` comes from `iri`, `{date}` is today as YYYY-MM-DD and `{index}`
+/// the position of the download in its batch.
+pub fn render_filename_template(template: &str, filename: &Path, iri: &IriString, index: usize) -> Result<PathBuf, RawstErr> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+
+        let close = rest[open..].find('}')
+            .ok_or_else(|| RawstErr::InvalidTemplate(format!("unclosed '{{' in '{}'", template)))?;
+        let placeholder = &rest[open + 1..open + close];
+
+        let value = match placeholder {
+            "name" => filename.to_string_lossy().into_owned(),
+            "stem" => filename.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            "ext" => filename.extension().unwrap_or_default().to_string_lossy().into_owned(),
+            // Ports aren't allowed in file names on every platform
+            "host" => iri.authority_components().map(|authority| authority.host().to_string()).unwrap_or_default(),
+            "date" => Local::now().format("%Y-%m-%d").to_string(),
+            "index" => index.to_string(),
+            other => return Err(RawstErr::InvalidTemplate(format!(
+                "unknown placeholder '{{{}}}', use {{name}}, {{stem}}, {{ext}}, {{host}}, {{date}} or {{index}}",
+                other
+            ))),
+        };
+        rendered.push_str(&value);
+
+        rest = &rest[open + close + 1..];
+    }
+    rendered.push_str(rest);
+
+    let rendered = sanitize_filename(&rendered);
+    match rendered.trim() {
+        "" | "." | ".." => Err(RawstErr::InvalidTemplate(format!("'{}' renders to an empty file name", template))),
+        name => Ok(PathBuf::from(name)),
+    }
+}
+
+/// Replaces the characters the platform doesn't allow in a file name with `_`
+///
+/// On Windows device names like `CON` or `nul.txt` get a `_` in front, and
+/// trailing dots and spaces, which Windows drops silently, are replaced too.
+pub fn sanitize_filename(name: &str) -> String {
+    #[cfg(windows)]
+    const ILLEGAL: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+    #[cfg(not(windows))]
+    const ILLEGAL: &[char] = &['/'];
+
+    let sanitized: String = name.chars()
+        .map(|c| if ILLEGAL.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+
+    #[cfg(windows)]
+    let sanitized = sanitize_windows_name(sanitized);
+
+    sanitized
+}
+
+// Names Windows opens as devices, whatever their extension
+#[cfg(windows)]
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[cfg(windows)]
+fn sanitize_windows_name(name: String) -> String {
+    let kept = name.trim_end_matches(['.', ' ']);
+    let mut sanitized = format!("{}{}", kept, "_".repeat(name.len() - kept.len()));
+
+    let stem = sanitized.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        sanitized.insert(0, '_');
+    }
+
+    sanitized
+}
+
+/// First of `filename`, `stem (1).ext`, `stem (2).ext`, ... free in `dir`
+pub fn unique_path(dir: &Path, filename: &Path) -> PathBuf {
+    unique_path_by(dir, filename, |path| !path.exists())
+}
+
+/// Like [`unique_path`], with `is_free` telling which paths can be used
+pub fn unique_path_by(dir: &Path, filename: &Path, is_free: impl Fn(&Path) -> bool) -> PathBuf {
+    let path = dir.join(filename);
+    if is_free(&path) {
+        return path;
+    }
+
+    let stem = filename.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = filename.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| is_free(path))
+        .unwrap()
+}
+
+// Characters of the download id kept in partial names, enough to tell downloads apart
+const PARTIAL_ID_LENGTH: usize = 16;
+
+/// Hidden name a file is written under until it's complete
+///
+/// eg. `archive.tar.gz` => `.archive.tar.gz.3f9a0c1e2b7d4a65.partial`, with
+/// the start of `download_id` so two downloads of the same name don't collide.
+pub fn partial_file_name(filename: &Path, download_id: &str) -> PathBuf {
+    let id = download_id.get(..PARTIAL_ID_LENGTH).unwrap_or(download_id);
+
+    let mut partial = OsString::from(".");
+    partial.push(filename.as_os_str());
+    partial.push(format!(".{}.partial", id));
+
+    PathBuf::from(partial)
+}
+
 pub fn chunk_file_name(hashed_filename: String, part: usize) -> PathBuf {
 
     PathBuf::from(hashed_filename).with_added_extension(format!("part{}", part))
 
 }
+
+/// Whether `path` looks like a name made by `chunk_file_name`
+pub fn is_chunk_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| extension.strip_prefix("part"))
+        .is_some_and(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+// Samples closer than this are merged so the buffer stays small for fast streams
+const SPEED_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Download speed over a sliding window of recent samples
+///
+/// Reflects current conditions instead of the average since the start, so
+/// the ETA recovers quickly after a stall or a throttled start.
+pub struct SpeedTracker {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+    latest: Option<(Instant, u64)>,
+}
+
+impl SpeedTracker {
+    pub fn new(window: Duration) -> Self {
+        SpeedTracker {
+            window,
+            samples: VecDeque::new(),
+            latest: None,
+        }
+    }
+
+    /// Records that `done` bytes were downloaded in total by now
+    pub fn record(&mut self, done: u64) {
+        self.record_at(Instant::now(), done);
+    }
+
+    pub fn record_at(&mut self, now: Instant, done: u64) {
+        if self.latest.is_some_and(|(_, latest)| done < latest) {
+            // Segments report concurrently and can arrive slightly out of order,
+            // only a reset to 0 means the download restarted
+            if done > 0 {
+                return;
+            }
+            self.samples.clear();
+        }
+
+        self.latest = Some((now, done));
+
+        let due = self.samples.back().is_none_or(|&(at, _)| now.duration_since(at) >= SPEED_SAMPLE_INTERVAL);
+        if due {
+            self.samples.push_back((now, done));
+        }
+
+        while self.samples.front().is_some_and(|&(at, _)| now.duration_since(at) > self.window) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the window, 0 until there are two samples apart in time
+    pub fn current_bps(&self) -> f64 {
+        let (Some(&(first_at, first)), Some((last_at, last))) = (self.samples.front(), self.latest) else {
+            return 0.0;
+        };
+
+        let elapsed = last_at.duration_since(first_at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+
+        last.saturating_sub(first) as f64 / elapsed
+    }
+
+    /// Time left at the current speed, `None` when the total is unknown or nothing is moving
+    pub fn eta(&self, total: Option<u64>, done: u64) -> Option<Duration> {
+        let remaining = total?.saturating_sub(done);
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+
+        let bps = self.current_bps();
+        if bps <= 0.0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(remaining as f64 / bps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    // A tracker that saw `steps` of (seconds since start, total bytes)
+    fn tracker(start: Instant, steps: &[(u64, u64)]) -> SpeedTracker {
+        let mut tracker = SpeedTracker::new(WINDOW);
+        for &(seconds, done) in steps {
+            tracker.record_at(start + Duration::from_secs(seconds), done);
+        }
+
+        tracker
+    }
+
+    #[test]
+    fn eta_is_unknown_without_a_total() {
+        let tracker = tracker(Instant::now(), &[(0, 0), (1, 1000), (2, 2000)]);
+
+        assert_eq!(tracker.current_bps(), 1000.0);
+        assert_eq!(tracker.eta(None, 2000), None);
+    }
+
+    #[test]
+    fn eta_is_unknown_while_nothing_moves() {
+        let start = Instant::now();
+
+        assert_eq!(tracker(start, &[]).eta(Some(1000), 0), None);
+        // A single sample has no speed yet
+        assert_eq!(tracker(start, &[(0, 500)]).eta(Some(1000), 500), None);
+        // Stalled for a whole window
+        let stalled = tracker(start, &[(0, 0), (1, 500), (10, 500), (16, 500)]);
+        assert_eq!(stalled.current_bps(), 0.0);
+        assert_eq!(stalled.eta(Some(1000), 500), None);
+    }
+
+    #[test]
+    fn eta_is_zero_once_nothing_remains() {
+        let start = Instant::now();
+
+        assert_eq!(tracker(start, &[]).eta(Some(1000), 1000), Some(Duration::ZERO));
+        assert_eq!(tracker(start, &[(0, 0), (1, 1000)]).eta(Some(1000), 1200), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn eta_follows_the_current_speed() {
+        let start = Instant::now();
+        let steady = tracker(start, &[(0, 0), (1, 1000), (2, 2000)]);
+
+        assert_eq!(steady.eta(Some(10_000), 2000), Some(Duration::from_secs(8)));
+
+        // A slow start leaves the window and stops dragging the speed down
+        let recovered = tracker(start, &[(0, 0), (10, 100), (11, 10_100), (12, 20_100)]);
+        assert_eq!(recovered.current_bps(), 10_000.0);
+    }
+
+    #[test]
+    fn a_reset_to_zero_clears_the_samples() {
+        let start = Instant::now();
+        let mut tracker = tracker(start, &[(0, 0), (1, 50_000), (2, 100_000)]);
+
+        tracker.record_at(start + Duration::from_secs(3), 0);
+        assert_eq!(tracker.current_bps(), 0.0);
+        assert_eq!(tracker.eta(Some(200_000), 0), None);
+
+        tracker.record_at(start + Duration::from_secs(4), 1000);
+        assert_eq!(tracker.current_bps(), 1000.0);
+        assert_eq!(tracker.eta(Some(200_000), 1000), Some(Duration::from_secs(199)));
+    }
+
+    #[test]
+    fn late_reports_of_fewer_bytes_are_ignored() {
+        let start = Instant::now();
+        let mut tracker = tracker(start, &[(0, 0), (2, 2000)]);
+
+        tracker.record_at(start + Duration::from_secs(2), 1500);
+        assert_eq!(tracker.current_bps(), 1000.0);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_device_names_get_a_prefix() {
+        assert_eq!(sanitize_filename("CON"), "_CON");
+        assert_eq!(sanitize_filename("NUL"), "_NUL");
+        assert_eq!(sanitize_filename("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_filename("com1.tar.gz"), "_com1.tar.gz");
+        // Only whole names are devices
+        assert_eq!(sanitize_filename("CONSOLE.txt"), "CONSOLE.txt");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_trailing_dots_and_spaces_are_replaced() {
+        assert_eq!(sanitize_filename("report."), "report_");
+        assert_eq!(sanitize_filename("report . "), "report___");
+        // Not a device name any more once the dot is gone
+        assert_eq!(sanitize_filename("NUL. "), "NUL__");
+        assert_eq!(sanitize_filename("a<b>c:d|e?f*.txt"), "a_b_c_d_e_f_.txt");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn slashes_and_control_characters_are_replaced() {
+        assert_eq!(sanitize_filename("a/b"), "a_b");
+        assert_eq!(sanitize_filename("/etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_filename("line\nbreak\ttab\u{7f}"), "line_break_tab_");
+        // Allowed outside of Windows
+        assert_eq!(sanitize_filename("CON"), "CON");
+        assert_eq!(sanitize_filename("a<b>:c?.txt "), "a<b>:c?.txt ");
+        assert_eq!(sanitize_filename("back\\slash"), "back\\slash");
+    }
+
+    const ADVERSARIAL_NAMES: [&str; 9] = [
+        "../x",
+        "..\\x",
+        "/etc/passwd",
+        "C:\\Windows\\win.ini",
+        "a/../../b",
+        ".",
+        "..",
+        " .. ",
+        "evil\u{0}name\n",
+    ];
+
+    #[test]
+    fn adversarial_names_are_unsafe() {
+        for name in ADVERSARIAL_NAMES {
+            assert!(is_unsafe_filename(name), "{:?} should be unsafe", name);
+        }
+
+        for name in ["file.tar.gz", "..hidden", "a..b", "report (1).pdf"] {
+            assert!(!is_unsafe_filename(name), "{:?} should be safe", name);
+        }
+    }
+
+    #[test]
+    fn sanitized_names_stay_inside_the_download_dir() {
+        let download_dir = Path::new("/srv/downloads");
+
+        for name in ADVERSARIAL_NAMES {
+            let Some(basename) = sanitize_basename(name) else {
+                continue;
+            };
+
+            assert!(is_plain_filename(&basename), "{:?} became {:?}", name, basename);
+            let path = download_dir.join(&basename);
+            assert_eq!(path.parent(), Some(download_dir), "{:?} became {:?}", name, path);
+            assert!(!basename.to_string_lossy().chars().any(char::is_control));
+        }
+
+        assert_eq!(sanitize_basename("../x"), Some(PathBuf::from("x")));
+        assert_eq!(sanitize_basename("..\\x"), Some(PathBuf::from("x")));
+        assert_eq!(sanitize_basename("/etc/passwd"), Some(PathBuf::from("passwd")));
+        assert_eq!(sanitize_basename("."), None);
+        assert_eq!(sanitize_basename("a/.."), None);
+        assert_eq!(sanitize_basename("evil\u{0}name\n"), Some(PathBuf::from("evil_name")));
+    }
+
+    #[test]
+    fn content_disposition_names_stay_inside_the_download_dir() {
+        for name in ADVERSARIAL_NAMES {
+            let mut headers = HeaderMap::new();
+            let value = format!("attachment; filename=\"{}\"", name.replace(char::is_control, ""));
+            headers.insert(CONTENT_DISPOSITION, HeaderValue::from_str(&value).unwrap());
+
+            if let Some(filename) = extract_filename_from_header(&headers) {
+                assert!(is_plain_filename(&filename), "{:?} became {:?}", name, filename);
+            }
+        }
+    }
+
+    const DOWNLOAD_ID: &str = "3f9a0c1e2b7d4a65e1d2c3b4a5968778";
+
+    #[test]
+    fn partial_names_are_hidden_and_carry_the_id() {
+        assert_eq!(
+            partial_file_name(Path::new("archive.tar.gz"), DOWNLOAD_ID),
+            PathBuf::from(".archive.tar.gz.3f9a0c1e2b7d4a65.partial"),
+        );
+        assert_eq!(
+            partial_file_name(Path::new("README"), DOWNLOAD_ID),
+            PathBuf::from(".README.3f9a0c1e2b7d4a65.partial"),
+        );
+    }
+
+    #[test]
+    fn partial_names_keep_short_ids_whole() {
+        assert_eq!(partial_file_name(Path::new("README"), "abc"), PathBuf::from(".README.abc.partial"));
+        assert_eq!(partial_file_name(Path::new("README"), ""), PathBuf::from(".README..partial"));
+    }
+
+    #[test]
+    fn directories_are_output_directories() {
+        let dir = crate::core::testing::TempDir::new();
+
+        assert!(is_directory_output(dir.path()));
+        // Not there yet, the separator says it's meant as one
+        assert!(is_directory_output(&dir.path().join("new").join("")));
+        assert!(is_directory_output(Path::new("downloads/")));
+    }
+
+    #[test]
+    fn files_and_stdout_are_not_output_directories() {
+        let dir = crate::core::testing::TempDir::new();
+        let file = dir.path().join("file.iso");
+        std::fs::write(&file, "").unwrap();
+
+        assert!(!is_directory_output(&file));
+        assert!(!is_directory_output(&dir.path().join("not-yet.iso")));
+        assert!(!is_directory_output(Path::new("downloads")));
+        assert!(!is_directory_output(Path::new("-")));
+    }
+}
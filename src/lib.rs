@@ -1,5 +1,7 @@
-#![feature(path_add_extension)]
 #![feature(thread_id_value)]
 
 pub mod cli;
 pub mod core;
+
+pub use crate::core::downloader::{Downloader, DownloaderBuilder};
+pub use crate::core::engine::DownloadSummary;